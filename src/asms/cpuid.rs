@@ -0,0 +1,59 @@
+#![allow(dead_code)]
+use core::arch::asm;
+
+/// CPUID leaves.
+pub const CPUID_FEATURES: u32 =     0x1;
+pub const CPUID_EXT_MAX: u32 =      0x80000000;
+pub const CPUID_EXT_FEATURES: u32 = 0x80000001;
+
+/// Feature bits in ecx and edx.
+pub const CPUID_ECX_PCID: u32 = 1 << 17;
+pub const CPUID_EDX_PGE: u32 =  1 << 13;
+
+/// Extended feature bits in edx.
+pub const CPUID_EXT_EDX_NX: u32 =      1 << 20;
+pub const CPUID_EXT_EDX_PDPE1GB: u32 = 1 << 26;
+
+/// Execute cpuid, return eax, ebx, ecx, edx.
+#[cfg(target_arch = "x86_64")]
+pub fn cpuid(leaf: u32, subleaf: u32) -> (u32, u32, u32, u32){
+    let eax: u32;
+    let ebx: u32;
+    let ecx: u32;
+    let edx: u32;
+
+    // rbx is reserved by the compiler, save it around cpuid.
+    unsafe{
+        asm!("mov {tmp:r}, rbx
+              cpuid
+              xchg {tmp:r}, rbx",
+              tmp = out(reg) ebx,
+              inout("eax") leaf => eax,
+              inout("ecx") subleaf => ecx,
+              out("edx") edx);
+    }
+
+    (eax, ebx, ecx, edx)
+}
+
+/// Check for 1GB pages.
+pub fn cpu_has_1g_pages() -> bool{
+    let (max_leaf, _, _, _) = cpuid(CPUID_EXT_MAX, 0);
+    if max_leaf < CPUID_EXT_FEATURES{
+        return false;
+    }
+    let (_, _, _, edx) = cpuid(CPUID_EXT_FEATURES, 0);
+    edx & CPUID_EXT_EDX_PDPE1GB != 0
+}
+
+/// Check for global pages.
+pub fn cpu_has_pge() -> bool{
+    let (_, _, _, edx) = cpuid(CPUID_FEATURES, 0);
+    edx & CPUID_EDX_PGE != 0
+}
+
+/// Check for process context identifiers.
+pub fn cpu_has_pcid() -> bool{
+    let (_, _, ecx, _) = cpuid(CPUID_FEATURES, 0);
+    ecx & CPUID_ECX_PCID != 0
+}
@@ -1,98 +1,94 @@
-use core::slice::{from_raw_parts_mut};
+#![allow(dead_code)]
+
 use core::arch::asm;
+use core::mem::size_of;
+use spin::Mutex;
+
+/// GDT Selectors, same layout as the boot GDT in kernel_entry.S.
+pub const GDT_KERNEL_CODE32: u16 = 0x08;
+pub const GDT_KERNEL_CODE: u16 =   0x10;
+pub const GDT_KERNEL_DATA: u16 =   0x18;
+pub const GDT_USER_DATA: u16 =     0x20;
+pub const GDT_USER_CODE: u16 =     0x28;
+pub const GDT_TSS: u16 =           0x30;
 
-/// GDT Selectors.
-pub const GDT_KERNEL_CODE: u64 = 0x08;
-pub const GDT_KERNEL_DATA: u64 = 0x10;
-pub const GDT_USER_DATA: u64   = 0x18;
-pub const GDT_USER_CODE: u64   = 0x20;
+/// IST slot of the double fault handler, 1-based as in the IDT.
+pub const IST_DOUBLE_FAULT: u8 = 1;
+
+/// Descriptor table pointer, used by lgdt and lidt.
+#[repr(C, packed)]
+pub struct DescriptorTablePointer{
+    pub limit: u16,
+    pub base: u64,
+}
 
 /// Load global descriptor table.
 #[cfg(target_arch = "x86_64")]
-pub fn lgdt(mut _val: u64){
+pub fn lgdt(ptr: &DescriptorTablePointer){
     unsafe{
-        asm!("lgdt {}", in(reg) _val);
+        asm!("lgdt [{}]", in(reg) ptr, options(readonly, nostack));
     }
 }
 
-/// Global Descriptor Entry.
-#[derive(Clone, Copy)]
-#[repr(C, packed)]
-pub struct GDE64{
-    limit_low: u16,
-    base_low: u16,
-    base_mid: u8,
-    access: u8,
-    flags_and_limit: u8,
-    base_high: u8,
-    base_extend: u32,
-    reserved: u32,
-}
-
-impl GDE64{
-    /// Create a new global descriptor entry.
-    pub fn new(limit: u64, base: u64, flags: u8, privilege: u8) -> Self{
-        Self{
-            limit_low: (limit & 0xffff) as u16,
-            base_low: (base & 0xffff) as u16,
-            base_mid: ((base >> 16) & 0xffff) as u8,
-            access: privilege, 
-            flags_and_limit: (((limit >> 16) & 0xf) | flags as u64) as u8,
-            base_high: ((base >> 24) & 0xffff) as u8,
-            base_extend: ((base >> 32) &0xffffffff) as u32,
-            reserved: 0,
-        }
+/// Load task register.
+#[cfg(target_arch = "x86_64")]
+pub fn ltr(selector: u16){
+    unsafe{
+        asm!("ltr {0:x}", in(reg) selector, options(nostack));
     }
 }
 
-/// Global Descriptor Table Pointer.
+/// Task State Segment.
 #[derive(Clone, Copy)]
 #[repr(C, packed)]
-pub struct GDTR{
-    gdtr: [u32; 3],
+pub struct TSS64{
+    reserved0: u32,
+    // Stacks for privilege levels 0-2.
+    pub rsp: [u64; 3],
+    reserved1: u64,
+    // Interrupt stacks, IST1-IST7.
+    pub ist: [u64; 7],
+    reserved2: u64,
+    reserved3: u16,
+    pub iomap_base: u16,
 }
 
-impl GDTR{
-    /// Create a new global descriptor table pointer.
-    pub fn new(num_entries: u32, gdt_base: u64) -> Self{
+impl TSS64{
+    /// Create an empty task state segment.
+    pub const fn new() -> Self{
         Self{
-            gdtr: [
-                num_entries,
-                (gdt_base & 0xffffffff) as u32,
-                ((gdt_base >> 32) & 0xffffffff) as u32,
-            ]
+            reserved0: 0,
+            rsp: [0; 3],
+            reserved1: 0,
+            ist: [0; 7],
+            reserved2: 0,
+            reserved3: 0,
+            iomap_base: size_of::<TSS64>() as u16,
         }
     }
-
-    /// Conert gdtr into u64.
-    pub fn to_u64(&self) -> u64{
-        unsafe{self.gdtr.as_ptr() as u64}
-    }
 }
 
-/// Total number of global descriptor entries.
-pub const NUM_GLOBAL_DESP_ENTRIES: usize = 6;
+/// Total number of global descriptor entries, the TSS takes two.
+pub const NUM_GLOBAL_DESP_ENTRIES: usize = 8;
 
 /// Global Descriptor Table.
 #[derive(Clone, Copy)]
-#[repr(C, packed)]
+#[repr(C, align(16))]
 pub struct GDT64{
-    entries: [GDE64; NUM_GLOBAL_DESP_ENTRIES],
-    gdtr: GDTR,
+    entries: [u64; NUM_GLOBAL_DESP_ENTRIES],
 }
 
 impl GDT64{
-    /// Create a new global descriptor table.
-    pub fn new() -> Self{
+    /// Create a new global descriptor table, without a TSS.
+    pub const fn new() -> Self{
         Self{
-            // MUST notice that the selector should be different with the init one.
-            entries: [GDE64::new(0, 0, 0, 0),
-                      GDE64::new(0xffff, 0, 0xc, 0x9a),  // KERNEL code (32-bit)
-                      GDE64::new(0xffff, 0, 0xa, 0x9a),  // KERNEL code (64-bit)
-                      GDE64::new(0xffff, 0, 0xc, 0x92),  // KERNEL data (64-bit)
-                      GDE64::new(0xffff, 0, 0xa, 0xfa),  // USER code (64-bit)
-                      GDE64::new(0xffff, 0, 0xc, 0xf2)], // USER data (64-bit)
-            gdtr: GDTR::new(NUM_GLOBAL_DESP_ENTRIES as u32, 0),
+            entries: [0x0000000000000000,
+                      0x00cf9b000000ffff,   // KERNEL code (32-bit)
+                      0x00af9b000000ffff,   // KERNEL code (64-bit)
+                      0x00cf93000000ffff,   // KERNEL data (64-bit)
+                      0x00cff3000000ffff,   // USER data (64-bit)
+                      0x00affb000000ffff,   // USER code (64-bit)
+                      0, 0],                // TSS
         }
     }
 
@@ -101,10 +97,66 @@ impl GDT64{
         (index << 3) as u16
     }
 
-    /// Enable this gdt.
-    pub fn enable(&mut self){
-        self.gdtr = GDTR::new(NUM_GLOBAL_DESP_ENTRIES as u32, self.entries.as_ptr() as u64);
-        let mut ptr: u64 = self.gdtr.to_u64();
-        lgdt(ptr);
+    /// Set the TSS descriptor.
+    pub fn set_tss(&mut self, tss: &'static TSS64){
+        let base: u64 = tss as *const TSS64 as u64;
+        let limit: u64 = (size_of::<TSS64>() - 1) as u64;
+        let index: usize = (GDT_TSS >> 3) as usize;
+        // Present, 64-bit available TSS.
+        self.entries[index] = (limit & 0xffff) | ((base & 0xffffff) << 16) | (0x89 << 40)
+            | (((limit >> 16) & 0xf) << 48) | (((base >> 24) & 0xff) << 56);
+        self.entries[index + 1] = base >> 32;
     }
-}
\ No newline at end of file
+
+    /// Enable this gdt, and reload segment registers.
+    pub fn enable(&'static self){
+        let ptr: DescriptorTablePointer = DescriptorTablePointer{
+            limit: (size_of::<GDT64>() - 1) as u16,
+            base: self.entries.as_ptr() as u64,
+        };
+        lgdt(&ptr);
+        unsafe{
+            asm!("push {code}
+                  lea {tmp}, [rip + 2f]
+                  push {tmp}
+                  retfq
+                  2:
+                  mov ds, {data:e}
+                  mov es, {data:e}
+                  mov ss, {data:e}",
+                  code = in(reg) GDT_KERNEL_CODE as u64,
+                  data = in(reg) GDT_KERNEL_DATA as u32,
+                  tmp = lateout(reg) _);
+        }
+    }
+}
+
+// Kernel GDT and TSS, only changed before being loaded. The CPU reads the
+// TSS in place, so both stay in statics.
+static GDT: Mutex<GDT64> = Mutex::new(GDT64::new());
+static TSS: Mutex<TSS64> = Mutex::new(TSS64::new());
+
+/// Load the kernel GDT with a TSS. The double fault handler runs on the
+/// stack ending at double_fault_stack.
+pub fn gdt_init(double_fault_stack: usize){
+    let mut tss = TSS.lock();
+    // Fields are unaligned, so copy them out and back.
+    let mut ist: [u64; 7] = tss.ist;
+    ist[(IST_DOUBLE_FAULT - 1) as usize] = double_fault_stack as u64;
+    tss.ist = ist;
+    let tss: &'static TSS64 = unsafe{ &*(&*tss as *const TSS64) };
+
+    let mut gdt = GDT.lock();
+    gdt.set_tss(tss);
+    let gdt: &'static GDT64 = unsafe{ &*(&*gdt as *const GDT64) };
+    gdt.enable();
+    ltr(GDT_TSS);
+}
+
+/// Set the stack used when entering the kernel from user mode.
+pub fn tss_set_kernel_stack(top: usize){
+    let mut tss = TSS.lock();
+    let mut rsp: [u64; 3] = tss.rsp;
+    rsp[0] = top as u64;
+    tss.rsp = rsp;
+}
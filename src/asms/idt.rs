@@ -1,12 +1,15 @@
 #![allow(dead_code)]
-#![allow(unused_variables)]
-
-#[macro_use]
 
 use core::arch::asm;
-use core::ops::{Index, IndexMut};
+use core::mem::size_of;
+use spin::Mutex;
 
-use super::gdt::{GDT_KERNEL_CODE};
+use super::gdt::{DescriptorTablePointer, GDT_KERNEL_CODE, IST_DOUBLE_FAULT};
+use crate::drivers::console::console::console_force_unlock;
+use crate::mm::fault::{page_fault_resolve, user_fault_handler, PageFault};
+use crate::mm::kstack::kstack_guard_hit;
+use crate::mm::page_table::rcr2;
+use crate::println;
 
 /// Clear interrupt flag.
 #[cfg(target_arch = "x86_64")]
@@ -26,9 +29,9 @@ pub fn sti(){
 
 /// Load idtr.
 #[cfg(target_arch = "x86_64")]
-pub fn lidt(mut _val: u64){
+pub fn lidt(ptr: &DescriptorTablePointer){
     unsafe{
-        asm!("lidt {}", in(reg) _val);
+        asm!("lidt [{}]", in(reg) ptr, options(readonly, nostack));
     }
 }
 
@@ -42,42 +45,6 @@ pub const NUM_INTERRUPT_DESP_ENTRIES: usize = 256;
 pub const MASTER_PIC_BOUND: u32 = 0x20;
 pub const SLAVE_PIC_BOUND: u32 = 0x28;
 
-/// Type for interrupt handler functions.
-pub type InterruptHandler = extern "C" fn(&mut IntrStackFrame) -> !;
-
-/// Handler without error code.
-macro_rules! handler_without_error_code{
-    ($name: ident) => {{
-        #[naked]
-        extern "C" fn handler_wrapper() -> !{
-            unsafe{
-                asm!("mov rdi, rsp
-                      sub rsp, 8
-                      call $0" :: "i"($name as extern "C" fn(&mut IntrStackFrame)->!)
-                      : "rdi" : "intel");
-            }
-        }
-        handler_wrapper()
-    }}
-}
-
-/// Handler with error code.
-macro_rules! handler_with_error_code{
-    ($name: ident) => {{
-        #[naked]
-        extern "C" fn handler_wrapper() -> !{
-            unsafe{
-                asm!("pop rsi
-                      mov rdi, rsp
-                      sub rsp, 8
-                      call $0" :: "i"($name as extern "C" fn(&mut IntrStackFrame)->!)
-                      : "rdi" : "intel");
-            }
-        }
-        handler_wrapper()
-    }}
-}
-
 pub enum InterruptTypes{
     IvDevideError,
     IvDebug,
@@ -107,53 +74,6 @@ pub enum InterruptTypes{
     IvSyscall = 0x80,
 }
 
-/// Interrupt Vectors.
-#[repr(C, packed)]
-pub struct IV64{
-    entries: [InterruptHandler; NUM_INTERRUPT_DESP_ENTRIES],
-}
-
-impl Index<usize> for IV64{
-    type Output = InterruptHandler;
-
-    fn index(&self, index: usize) -> &InterruptHandler{
-        &self.entries[index]
-    }
-}
-
-impl IndexMut<usize> for IV64{
-    fn index_mut(&mut self, index: usize) -> &mut InterruptHandler{
-        &mut self.entries[index]
-    }
-}
-
-/// Temporary code.
-extern "C"{
-    pub fn timer_apic();
-}
-
-impl IV64{
-    /// Create a new interrupt vector.
-    pub fn new() -> Self{
-        Self{
-            entries: [0; NUM_INTERRUPT_DESP_ENTRIES],
-        }
-    }
-
-    /// Setup the interrupt vector in default.
-    pub fn default_setup(&mut self){
-    }
-
-    /// Set one interrupt handler.
-    pub fn set_handler(&mut self, intr_type: InterruptTypes, with_error_code: bool, handler: InterruptHandler){
-        if with_error_code{
-            self.entries[intr_type as usize] = handler_with_error_code!(handler);
-        }else{
-            self.entries[intr_type as usize] = handler_without_error_code!(handler);
-        }
-    }
-}
-
 /// Interrupt Descriptor Entry.
 #[derive(Clone, Copy)]
 #[repr(C, packed)]
@@ -169,122 +89,126 @@ pub struct IDE64{
 
 impl IDE64{
     /// Create a new Interrupt Descriptor Entry.
-    pub fn new(offset: u64, gdt_selector: u16, ist_offset: u8, privilege: u8)-> Self{
+    pub const fn new(offset: u64, gdt_selector: u16, ist_offset: u8, privilege: u8)-> Self{
         Self{
             offset_low: (offset & 0xffff) as u16,
             selector: gdt_selector,
             ist: ist_offset,
             gate_and_dpl: privilege,
             offset_mid: ((offset >> 16) & 0xffff) as u16,
-            offset_high: ((offset >> 32) & 0xffff) as u32,
+            offset_high: ((offset >> 32) & 0xffffffff) as u32,
             reserved: 0
         }
     }
 
     /// Create a null Interrupt Descriptor Entry.
-    pub fn null() -> Self{
-        Self{ 
+    pub const fn null() -> Self{
+        Self{
             offset_low: 0, selector: 0, ist: 0, gate_and_dpl: 0, offset_mid: 0,
             offset_high: 0, reserved: 0
         }
     }
 }
 
-/// Interrupt Descriptor Table Pointer.
-#[derive(Clone, Copy)]
-#[repr(C, packed)]
-pub struct IDTR{
-    idtr: [u32; 3],
-}
-
-impl IDTR{
-    /// Create a null idtr.
-    pub fn null() -> Self{
-        Self{
-            idtr: [0, 0, 0,]
-        }
-    }
-
-    /// Create a new idtr.
-    pub fn new(num_entries: u32, idt_base: u64) -> Self{
-        Self{
-            idtr: [
-                num_entries,
-                (idt_base & 0xffffffff) as u32,
-                ((idt_base >> 32) & 0xffffffff) as u32,
-            ]
-        }
-    }
-
-    /// Convert to pointer.
-    pub fn to_ptr(&self) -> *mut u32{
-        self.idtr.as_ptr() as *mut u32
-    }
-
-    /// Conver to u64.
-    pub fn to_u64(&self) -> u64{
-        self.idtr.as_ptr() as u64
-    }
-
-}
-
 /// Interrupt Descriptor Table.
-#[repr(C, packed)]
+#[repr(C, align(16))]
 pub struct IDT64{
-    iv: IV64,
     entries: [IDE64; NUM_INTERRUPT_DESP_ENTRIES],
-    idtr: IDTR,
 }
 
 impl IDT64{
-    /// Create a new idt.
-    pub fn new() -> Self{
+    /// Create an empty idt.
+    pub const fn new() -> Self{
         Self{
-            iv: IV64::new(),
             entries: [IDE64::null(); NUM_INTERRUPT_DESP_ENTRIES],
-            idtr: IDTR::null(),
         }
     }
 
-    /// Defaultly setup the idt.
-    pub fn default_setup(&mut self){
-        // Setup interrupt vector.
-        self.iv.default_setup();
-
-        for i in 0..NUM_INTERRUPT_DESP_ENTRIES{
-            self.entries[i] = IDE64::new(self.iv[i] as u64, GDT_KERNEL_CODE as u16, 0, ATTR_INT_GATE);
-        }
-    }
-
-    /// Set a particular interrupt handler.
-    pub fn set_handler(&mut self, with_error_code: bool, 
-        intr_type: InterruptTypes, handler: InterruptHandler){
-        self.iv.set_handler(intr_type, with_error_code, handler);
-        self.entries[i] = IDE64::new(self.iv[intr_type as usize] as u64, 
-            GDT_KERNEL_CODE as u16, 0, ATTR_INT_GATE);
+    /// Set a particular interrupt handler. ist is 0 to stay on the current
+    /// stack, or the IST slot to switch to.
+    pub fn set_handler(&mut self, intr_type: InterruptTypes, handler: u64, ist: u8){
+        self.entries[intr_type as usize] = IDE64::new(handler, GDT_KERNEL_CODE, ist, ATTR_INT_GATE);
     }
 
     /// Enable the idt.
-    pub fn enable(&mut self){
-        self.idtr = IDTR::new(NUM_INTERRUPT_DESP_ENTRIES as u32, self.entries.as_ptr() as u64);
-        let mut ptr: u64 = self.idtr.to_u64();
-        lidt(ptr);
+    pub fn enable(&'static self){
+        let ptr: DescriptorTablePointer = DescriptorTablePointer{
+            limit: (size_of::<IDT64>() - 1) as u16,
+            base: self.entries.as_ptr() as u64,
+        };
+        lidt(&ptr);
     }
 }
 
 /// Interrupt Stack Frame.
-#[derive(Clone, Copy)]
-#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
 pub struct IntrStackFrame{
-    rip: u64,
-    cs: u64,
-    rflags: u64,
-    rsp: u64,
-    ss: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+/// Double fault handler, runs on its own IST stack. A kernel stack overflow
+/// faults on the guard page, and then again when pushing the page fault
+/// frame, which turns into a double fault.
+pub extern "x86-interrupt" fn double_fault_handler(frame: IntrStackFrame, _error_code: u64) -> !{
+    // The fault may have hit while printing.
+    console_force_unlock();
+    let fault_addr: usize = rcr2();
+    println!("\n[Err] Double fault at rip: {:x}, rsp: {:x}, cr2: {:x}", frame.rip, frame.rsp, fault_addr);
+    if kstack_guard_hit(fault_addr) || kstack_guard_hit(frame.rsp as usize){
+        println!("[Err] Kernel stack overflow.");
+    }
+    loop{
+        cli();
+        unsafe{
+            asm!("hlt");
+        }
+    }
+}
+
+/// Page fault handler. Faults in the regions of the current address space
+/// are resolved, the rest are reported, and the process gets them when user
+/// code faulted.
+pub extern "x86-interrupt" fn page_fault_handler(frame: IntrStackFrame, error_code: u64){
+    let fault: PageFault = PageFault{ addr: rcr2(), rip: frame.rip as usize, error_code: error_code };
+    let err = match page_fault_resolve(&fault){
+        Ok(_) => { return ; }
+        Err(err) => err,
+    };
+    if fault.is_user(){
+        if let Some(handler) = user_fault_handler(){
+            handler(&fault, err);
+        }
+    }
+
+    // The fault may have hit while printing.
+    console_force_unlock();
+    println!("");
+    fault.print();
+    println!("[Err] Unresolved page fault: {:?}, rsp: {:x}", err, frame.rsp);
+    loop{
+        cli();
+        unsafe{
+            asm!("hlt");
+        }
+    }
 }
 
-/// For test only.
-pub extern "C" fn divide_by_zero_handler(intr_frame: &mut IntrStackFrame) -> !{
-    println!("This is divide by zero handler.");
-    loop{}
-}
\ No newline at end of file
+// Kernel IDT, only changed before being loaded.
+static IDT: Mutex<IDT64> = Mutex::new(IDT64::new());
+
+/// Setup and load the interrupt descriptor table.
+pub fn idt_init(){
+    let double_fault: extern "x86-interrupt" fn(IntrStackFrame, u64) -> ! = double_fault_handler;
+    let page_fault: extern "x86-interrupt" fn(IntrStackFrame, u64) = page_fault_handler;
+    let mut idt = IDT.lock();
+    idt.set_handler(InterruptTypes::IvDoubleFault, double_fault as usize as u64, IST_DOUBLE_FAULT);
+    idt.set_handler(InterruptTypes::IVPageFault, page_fault as usize as u64, 0);
+    // The table lives in a static, so it stays valid once loaded.
+    let idt: &'static IDT64 = unsafe{ &*(&*idt as *const IDT64) };
+    idt.enable();
+}
@@ -0,0 +1,20 @@
+#![allow(dead_code)]
+use core::arch::asm;
+
+/// Write a byte to an I/O port.
+#[cfg(target_arch = "x86_64")]
+pub fn outb(port: u16, val: u8){
+    unsafe{
+        asm!("out dx, al", in("dx") port, in("al") val);
+    }
+}
+
+/// Read a byte from an I/O port.
+#[cfg(target_arch = "x86_64")]
+pub fn inb(port: u16) -> u8{
+    let val: u8;
+    unsafe{
+        asm!("in al, dx", out("al") val, in("dx") port);
+    }
+    val
+}
@@ -1 +1,5 @@
-pub mod msr;
\ No newline at end of file
+pub mod msr;
+pub mod io;
+pub mod gdt;
+pub mod idt;
+pub mod cpuid;
\ No newline at end of file
@@ -6,6 +6,11 @@ pub const MSR_STAR: u32   = 0xC0000081;
 pub const MSR_LSTAR: u32  = 0xC0000082;
 pub const MSR_SFMASK: u32 = 0xC0000084;
 
+/// EFER bits.
+pub const EFER_SCE: u64 = 1;
+pub const EFER_LME: u64 = 1 << 8;
+pub const EFER_NXE: u64 = 1 << 11;
+
 /// Read Model-specific register
 #[cfg(target_arch = "x86_64")]
 pub fn rdmsr(_reg: u32) -> u64{
@@ -26,6 +31,6 @@ pub fn wrmsr(_reg: u32, val: u64){
     let high: u32 = (val >> 32) as u32;
 
     unsafe{
-        asm!("wrmsr", in("ecx") _reg, in("eax") low, in("edx") _reg);
+        asm!("wrmsr", in("ecx") _reg, in("eax") low, in("edx") high);
     }
 }
\ No newline at end of file
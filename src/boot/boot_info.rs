@@ -0,0 +1,272 @@
+#![allow(dead_code)]
+
+use core::str::from_utf8;
+use spin::Mutex;
+
+use crate::println;
+
+/// Maximum number of memory areas.
+pub const MAX_MEM_AREAS: usize = 128;
+/// Maximum number of kernel ELF sections.
+pub const MAX_ELF_SECTIONS: usize = 48;
+/// Maximum number of boot modules.
+pub const MAX_MODULES: usize = 16;
+
+/// ELF section flags.
+pub const ELF_SECTION_WRITABLE: u64 =   1;
+pub const ELF_SECTION_ALLOCATED: u64 =  1 << 1;
+pub const ELF_SECTION_EXECUTABLE: u64 = 1 << 2;
+
+/// Errors found while building boot information.
+#[derive(Debug, Clone, Copy)]
+pub enum BootError{
+    // Bootloader magic doesn't match any supported protocol.
+    BadMagic(u32),
+    // Boot information structure cannot be parsed.
+    BadInfo,
+    // No memory map is provided.
+    NoMemoryMap,
+    // EFI boot services call failed with a status.
+    EfiError(usize),
+}
+
+/// Fixed-size string copied out of boot information.
+#[derive(Clone, Copy)]
+pub struct BootStr<const N: usize>{
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> BootStr<N>{
+    /// Create an empty string.
+    pub const fn new() -> Self{
+        Self{ buf: [0; N], len: 0 }
+    }
+
+    /// Copy a string, cut at N bytes.
+    pub fn from_str(s: &str) -> Self{
+        let mut result = Self::new();
+        result.set(s);
+        result
+    }
+
+    /// Replace content, cut at N bytes.
+    pub fn set(&mut self, s: &str){
+        let mut len: usize = if s.len() < N { s.len() } else { N };
+        while !s.is_char_boundary(len){
+            len -= 1;
+        }
+        self.buf[..len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len = len;
+    }
+
+    /// View as str.
+    pub fn as_str(&self) -> &str{
+        from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+
+    /// Check empty.
+    pub fn is_empty(&self) -> bool{
+        self.len == 0
+    }
+}
+
+/// Type of a physical memory area.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MemAreaType{
+    Available,
+    Reserved,
+    AcpiReclaimable,
+    AcpiNvs,
+    Defective,
+}
+
+/// Physical memory area [start, end).
+#[derive(Clone, Copy)]
+pub struct MemArea{
+    pub start: usize,
+    pub end: usize,
+    pub area_type: MemAreaType,
+}
+
+impl MemArea{
+    const fn empty() -> Self{
+        Self{ start: 0, end: 0, area_type: MemAreaType::Reserved }
+    }
+}
+
+/// Section of the kernel ELF image.
+#[derive(Clone, Copy)]
+pub struct ElfSection{
+    pub name: BootStr<16>,
+    pub start: usize,
+    pub end: usize,
+    pub flags: u64,
+}
+
+impl ElfSection{
+    const fn empty() -> Self{
+        Self{ name: BootStr::new(), start: 0, end: 0, flags: 0 }
+    }
+
+    /// Check a flag.
+    #[inline]
+    pub fn is_contain(&self, flag: u64) -> bool{
+        self.flags & flag != 0
+    }
+}
+
+/// Module loaded by the bootloader at physical [start, end).
+#[derive(Clone, Copy)]
+pub struct BootModule{
+    pub name: BootStr<64>,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl BootModule{
+    const fn empty() -> Self{
+        Self{ name: BootStr::new(), start: 0, end: 0 }
+    }
+}
+
+/// Pixel layout of a framebuffer.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FramebufferKind{
+    Indexed,
+    // (position, size) of red, green and blue bits.
+    Rgb{ red: (u8, u8), green: (u8, u8), blue: (u8, u8) },
+    Text,
+}
+
+/// Framebuffer set up by the bootloader.
+#[derive(Clone, Copy)]
+pub struct FramebufferInfo{
+    pub addr: usize,
+    pub pitch: usize,
+    pub width: usize,
+    pub height: usize,
+    pub bpp: u8,
+    pub kind: FramebufferKind,
+}
+
+/// Everything the kernel learns from the bootloader, validated once at entry.
+pub struct BootInfo{
+    // Physical range of the raw boot information.
+    pub info_start: usize,
+    pub info_end: usize,
+    pub mem_areas: [MemArea; MAX_MEM_AREAS],
+    pub num_mem_areas: usize,
+    pub elf_sections: [ElfSection; MAX_ELF_SECTIONS],
+    pub num_elf_sections: usize,
+    pub modules: [BootModule; MAX_MODULES],
+    pub num_modules: usize,
+    pub cmdline: BootStr<256>,
+    pub bootloader_name: BootStr<64>,
+    pub framebuffer: Option<FramebufferInfo>,
+    // Physical address of the ACPI RSDP, and its revision.
+    pub rsdp: Option<(usize, u8)>,
+    // Physical address of the EFI system table, when booted from UEFI.
+    pub efi_system_table: Option<usize>,
+}
+
+impl BootInfo{
+    /// Create an empty boot information.
+    pub const fn empty() -> Self{
+        Self{
+            info_start: 0,
+            info_end: 0,
+            mem_areas: [MemArea::empty(); MAX_MEM_AREAS],
+            num_mem_areas: 0,
+            elf_sections: [ElfSection::empty(); MAX_ELF_SECTIONS],
+            num_elf_sections: 0,
+            modules: [BootModule::empty(); MAX_MODULES],
+            num_modules: 0,
+            cmdline: BootStr::new(),
+            bootloader_name: BootStr::new(),
+            framebuffer: None,
+            rsdp: None,
+            efi_system_table: None,
+        }
+    }
+
+    /// Add a memory area. It is merged into the previous one if they are
+    /// adjacent and of the same type, EFI maps are long.
+    pub fn add_mem_area(&mut self, start: usize, end: usize, area_type: MemAreaType){
+        if self.num_mem_areas > 0{
+            let last: &mut MemArea = &mut self.mem_areas[self.num_mem_areas - 1];
+            if last.area_type == area_type && last.end == start{
+                last.end = end;
+                return ;
+            }
+        }
+        if self.num_mem_areas < MAX_MEM_AREAS{
+            self.mem_areas[self.num_mem_areas] = MemArea{ start: start, end: end, area_type: area_type };
+            self.num_mem_areas += 1;
+        }
+        else{
+            println!("[Err] Too many memory areas, drop 0x{:x}-0x{:x}.", start, end);
+        }
+    }
+
+    /// Add a kernel ELF section.
+    pub fn add_elf_section(&mut self, name: &str, start: usize, end: usize, flags: u64){
+        if self.num_elf_sections < MAX_ELF_SECTIONS{
+            self.elf_sections[self.num_elf_sections] = ElfSection{
+                name: BootStr::from_str(name), start: start, end: end, flags: flags };
+            self.num_elf_sections += 1;
+        }
+    }
+
+    /// Add a boot module.
+    pub fn add_module(&mut self, name: &str, start: usize, end: usize){
+        if self.num_modules < MAX_MODULES{
+            self.modules[self.num_modules] = BootModule{
+                name: BootStr::from_str(name), start: start, end: end };
+            self.num_modules += 1;
+        }
+        else{
+            println!("[Err] Too many modules, drop {}.", name);
+        }
+    }
+
+    /// Memory areas.
+    pub fn mem_areas(&self) -> &[MemArea]{
+        &self.mem_areas[..self.num_mem_areas]
+    }
+
+    /// Kernel ELF sections.
+    pub fn elf_sections(&self) -> &[ElfSection]{
+        &self.elf_sections[..self.num_elf_sections]
+    }
+
+    /// Boot modules.
+    pub fn modules(&self) -> &[BootModule]{
+        &self.modules[..self.num_modules]
+    }
+
+    /// Print a summary.
+    pub fn print(&self){
+        println!("[+] Bootloader: {}", self.bootloader_name.as_str());
+        println!("[+] Command line: {}", self.cmdline.as_str());
+        for area in self.mem_areas(){
+            println!("[+] start: 0x{:x}, end: 0x{:x}, type: {:?}", area.start, area.end, area.area_type);
+        }
+        for module in self.modules(){
+            println!("[+] Module {}: 0x{:x} - 0x{:x}", module.name.as_str(), module.start, module.end);
+        }
+        if let Some(fb) = self.framebuffer{
+            println!("[+] Framebuffer: 0x{:x}, {}x{}x{}, pitch {}, {:?}", fb.addr, fb.width, fb.height,
+                fb.bpp, fb.pitch, fb.kind);
+        }
+        if let Some((rsdp, revision)) = self.rsdp{
+            println!("[+] ACPI RSDP: 0x{:x}, revision {}", rsdp, revision);
+        }
+        if let Some(system_table) = self.efi_system_table{
+            println!("[+] EFI system table: 0x{:x}", system_table);
+        }
+    }
+}
+
+// Boot information, built once at kernel entry.
+pub static BOOT_INFO: Mutex<BootInfo> = Mutex::new(BootInfo::empty());
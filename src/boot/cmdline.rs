@@ -0,0 +1,224 @@
+#![allow(dead_code)]
+
+use spin::Mutex;
+
+use super::boot_info::BootStr;
+use crate::mm::kstack::{KSTACK_DEFAULT_SIZE, KSTACK_MAX_SIZE};
+use crate::println;
+
+/// Where console output goes.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ConsoleKind{
+    Screen,
+    Serial,
+    Both,
+}
+
+/// How much the kernel reports.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum LogLevel{
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+/// Self tests run at boot.
+pub const SELFTEST_ALLOC: u32 =  1;
+pub const SELFTEST_HEAP: u32 =   1 << 1;
+pub const SELFTEST_PAGING: u32 = 1 << 2;
+pub const SELFTEST_ALL: u32 =    SELFTEST_ALLOC | SELFTEST_HEAP | SELFTEST_PAGING;
+/// Overflows the kernel stack on purpose, so it is not part of all.
+pub const SELFTEST_STACK: u32 =  1 << 3;
+
+/// Options given on the kernel command line.
+#[derive(Clone, Copy)]
+pub struct KernelOptions{
+    // console=screen|serial|both
+    pub console: ConsoleKind,
+    // loglevel=error|warn|info|debug
+    pub loglevel: LogLevel,
+    // mem=<size>[K|M|G], ignore memory above it.
+    pub mem_limit: Option<usize>,
+    // smp=<n>, number of CPUs to bring up.
+    pub smp: usize,
+    // kstack=<size>[K|M], size of kernel stacks.
+    pub kstack_size: usize,
+    // selftest=<test>[,<test>...], from alloc, heap, paging, stack, all.
+    pub selftest: u32,
+    // init=<path>, first user program.
+    pub init: BootStr<64>,
+}
+
+impl KernelOptions{
+    /// Options used when nothing is given.
+    pub const fn default() -> Self{
+        Self{
+            console: ConsoleKind::Screen,
+            loglevel: LogLevel::Info,
+            mem_limit: None,
+            smp: 1,
+            kstack_size: KSTACK_DEFAULT_SIZE,
+            selftest: 0,
+            init: BootStr::new(),
+        }
+    }
+
+    /// Check whether a self test is selected.
+    pub fn selftest(&self, test: u32) -> bool{
+        self.selftest & test != 0
+    }
+
+    /// Print all options.
+    pub fn print(&self){
+        println!("[+] Options: console={:?}, loglevel={:?}, smp={}, kstack={} KB, selftest=0x{:x}, init={}",
+            self.console, self.loglevel, self.smp, self.kstack_size / 1024, self.selftest, self.init.as_str());
+        if let Some(limit) = self.mem_limit{
+            println!("[+] Options: mem={} KB", limit / 1024);
+        }
+    }
+}
+
+/// Parser of one option value. Return false if the value is invalid.
+type OptionParser = fn(&mut KernelOptions, &str) -> bool;
+
+/// Every known option.
+const OPTION_TABLE: [(&str, OptionParser); 7] = [
+    ("console", parse_console),
+    ("loglevel", parse_loglevel),
+    ("mem", parse_mem),
+    ("smp", parse_smp),
+    ("kstack", parse_kstack),
+    ("selftest", parse_selftest),
+    ("init", parse_init),
+];
+
+/// Parse a number, decimal or hex with 0x prefix.
+fn parse_number(value: &str) -> Option<usize>{
+    if value.starts_with("0x"){
+        usize::from_str_radix(&value[2..], 16).ok()
+    }
+    else{
+        usize::from_str_radix(value, 10).ok()
+    }
+}
+
+/// Parse a size with an optional K, M or G suffix.
+pub fn parse_size(value: &str) -> Option<usize>{
+    let (number, shift) = match value.as_bytes().last(){
+        Some(b'K') | Some(b'k') => (&value[..value.len() - 1], 10),
+        Some(b'M') | Some(b'm') => (&value[..value.len() - 1], 20),
+        Some(b'G') | Some(b'g') => (&value[..value.len() - 1], 30),
+        _ => (value, 0),
+    };
+    parse_number(number)?.checked_mul(1 << shift)
+}
+
+fn parse_console(options: &mut KernelOptions, value: &str) -> bool{
+    options.console = match value{
+        "screen" => ConsoleKind::Screen,
+        "serial" => ConsoleKind::Serial,
+        "both" => ConsoleKind::Both,
+        _ => { return false; }
+    };
+    true
+}
+
+fn parse_loglevel(options: &mut KernelOptions, value: &str) -> bool{
+    options.loglevel = match value{
+        "error" => LogLevel::Error,
+        "warn" => LogLevel::Warn,
+        "info" => LogLevel::Info,
+        "debug" => LogLevel::Debug,
+        _ => { return false; }
+    };
+    true
+}
+
+fn parse_mem(options: &mut KernelOptions, value: &str) -> bool{
+    match parse_size(value){
+        Some(limit) if limit > 0 => {
+            options.mem_limit = Some(limit);
+            true
+        }
+        _ => false,
+    }
+}
+
+fn parse_smp(options: &mut KernelOptions, value: &str) -> bool{
+    match parse_number(value){
+        Some(cpus) if cpus > 0 => {
+            options.smp = cpus;
+            true
+        }
+        _ => false,
+    }
+}
+
+fn parse_kstack(options: &mut KernelOptions, value: &str) -> bool{
+    match parse_size(value){
+        Some(size) if size > 0 && size <= KSTACK_MAX_SIZE => {
+            options.kstack_size = size;
+            true
+        }
+        _ => false,
+    }
+}
+
+fn parse_selftest(options: &mut KernelOptions, value: &str) -> bool{
+    for test in value.split(','){
+        options.selftest |= match test{
+            "alloc" => SELFTEST_ALLOC,
+            "heap" => SELFTEST_HEAP,
+            "paging" => SELFTEST_PAGING,
+            "stack" => SELFTEST_STACK,
+            "all" => SELFTEST_ALL,
+            _ => { return false; }
+        };
+    }
+    true
+}
+
+fn parse_init(options: &mut KernelOptions, value: &str) -> bool{
+    if value.is_empty(){
+        return false;
+    }
+    options.init.set(value);
+    true
+}
+
+/// Parse a command line. Unknown keys and invalid values are reported and skipped.
+pub fn parse_cmdline(cmdline: &str) -> KernelOptions{
+    let mut options: KernelOptions = KernelOptions::default();
+    for arg in cmdline.split_whitespace(){
+        let (key, value) = match arg.find('='){
+            Some(pos) => (&arg[..pos], &arg[pos + 1..]),
+            _ => (arg, ""),
+        };
+
+        match OPTION_TABLE.iter().find(|(name, _)| *name == key){
+            Some((_, parser)) => {
+                if !parser(&mut options, value){
+                    println!("[Err] Invalid value for kernel option {}: {}", key, value);
+                }
+            }
+            _ => {
+                println!("[Err] Unknown kernel option: {}", arg);
+            }
+        }
+    }
+    options
+}
+
+// Options of this boot.
+static KERNEL_OPTIONS: Mutex<KernelOptions> = Mutex::new(KernelOptions::default());
+
+/// Parse the kernel command line and keep the result.
+pub fn cmdline_init(cmdline: &str){
+    *KERNEL_OPTIONS.lock() = parse_cmdline(cmdline);
+}
+
+/// Get options of this boot.
+pub fn kernel_options() -> KernelOptions{
+    *KERNEL_OPTIONS.lock()
+}
@@ -0,0 +1,118 @@
+#![allow(dead_code)]
+
+use core::mem::size_of;
+
+use super::boot_info::{BootError, BootInfo, MemAreaType};
+
+extern "C"{
+    /// Load the kernel GDT and segment registers, defined in kernel_entry.S.
+    fn load_kernel_gdt();
+    /// Result of leaving boot services in the UEFI entry stub.
+    static efi_exit: EfiExit;
+    /// Final memory map, written by the UEFI entry stub.
+    static efi_mmap: [u8; EFI_MMAP_SIZE];
+}
+
+/// EFI status of success.
+pub const EFI_SUCCESS: usize = 0;
+/// Size of the buffer receiving the final memory map, as in kernel_entry.S.
+pub const EFI_MMAP_SIZE: usize = 0x4000;
+/// Status left by the entry stub when it did not leave boot services.
+pub const EFI_NOT_EXITED: usize = usize::MAX;
+
+/// EFI memory types.
+pub const EFI_LOADER_CODE: u32 =          1;
+pub const EFI_LOADER_DATA: u32 =          2;
+pub const EFI_BOOT_SERVICES_CODE: u32 =   3;
+pub const EFI_BOOT_SERVICES_DATA: u32 =   4;
+pub const EFI_CONVENTIONAL_MEMORY: u32 =  7;
+pub const EFI_UNUSABLE_MEMORY: u32 =      8;
+pub const EFI_ACPI_RECLAIM_MEMORY: u32 =  9;
+pub const EFI_ACPI_MEMORY_NVS: u32 =      10;
+
+/// What the UEFI entry stub got from GetMemoryMap and ExitBootServices.
+#[repr(C)]
+struct EfiExit{
+    status: usize,
+    map_size: usize,
+    desc_size: usize,
+    desc_version: usize,
+    map_key: usize,
+}
+
+/// EFI memory descriptor. The real descriptor size is given with the map.
+#[repr(C)]
+struct EfiMemoryDescriptor{
+    typ: u32,
+    pad: u32,
+    phys_start: u64,
+    virt_start: u64,
+    num_pages: u64,
+    attribute: u64,
+}
+
+/// Header of the multiboot2 EFI memory map tag.
+#[repr(C)]
+struct EfiMemoryMapTag{
+    typ: u32,
+    size: u32,
+    desc_size: u32,
+    desc_version: u32,
+}
+
+/// Convert an EFI memory type. Loader and boot services memory is free once
+/// boot services are gone; what the kernel still needs is reserved later.
+fn mem_area_type(typ: u32) -> MemAreaType{
+    match typ{
+        EFI_LOADER_CODE | EFI_LOADER_DATA | EFI_BOOT_SERVICES_CODE | EFI_BOOT_SERVICES_DATA
+            | EFI_CONVENTIONAL_MEMORY => MemAreaType::Available,
+        EFI_ACPI_RECLAIM_MEMORY => MemAreaType::AcpiReclaimable,
+        EFI_ACPI_MEMORY_NVS => MemAreaType::AcpiNvs,
+        EFI_UNUSABLE_MEMORY => MemAreaType::Defective,
+        _ => MemAreaType::Reserved,
+    }
+}
+
+/// Add memory areas from a raw EFI memory map.
+fn add_efi_mem_areas(info: &mut BootInfo, map: *const u8, map_size: usize, desc_size: usize){
+    if desc_size < size_of::<EfiMemoryDescriptor>(){
+        return ;
+    }
+    let mut offset: usize = 0;
+    while offset + desc_size <= map_size{
+        let desc: &EfiMemoryDescriptor = unsafe{ &*(map.add(offset) as *const EfiMemoryDescriptor) };
+        let start: usize = desc.phys_start as usize;
+        let end: usize = start + desc.num_pages as usize * 4096;
+        info.add_mem_area(start, end, mem_area_type(desc.typ));
+        offset += desc_size;
+    }
+}
+
+/// Add memory areas from the multiboot2 EFI memory map tag at virtual address tag.
+pub fn load_efi_mmap_tag(info: &mut BootInfo, tag: usize){
+    let header: &EfiMemoryMapTag = unsafe{ &*(tag as *const EfiMemoryMapTag) };
+    let map_size: usize = header.size as usize - size_of::<EfiMemoryMapTag>();
+    add_efi_mem_areas(info, (tag + size_of::<EfiMemoryMapTag>()) as *const u8, map_size,
+        header.desc_size as usize);
+}
+
+/// Take the final memory map from leaving EFI boot services. The entry stub
+/// leaves them while the firmware page table is still loaded.
+pub fn efi_exit_boot_services(info: &mut BootInfo) -> Result<(), BootError>{
+    let exit: &EfiExit = unsafe{ &efi_exit };
+    match exit.status{
+        EFI_SUCCESS => {}
+        EFI_NOT_EXITED => { return Err(BootError::BadInfo); }
+        status => { return Err(BootError::EfiError(status)); }
+    }
+
+    // The firmware GDT belongs to boot services memory.
+    unsafe{
+        core::arch::asm!("cli");
+        load_kernel_gdt();
+    }
+
+    let map: &[u8; EFI_MMAP_SIZE] = unsafe{ &efi_mmap };
+    add_efi_mem_areas(info, map.as_ptr(), exit.map_size.min(EFI_MMAP_SIZE), exit.desc_size);
+    Ok(())
+}
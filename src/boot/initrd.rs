@@ -0,0 +1,104 @@
+#![allow(dead_code)]
+
+use core::slice::from_raw_parts;
+use spin::Mutex;
+
+use super::boot_info::{BootInfo, BootStr, MAX_MODULES};
+use crate::mm::page_table_entry::PhysAddr;
+use crate::mm::phys_page::phys_to_virt;
+use crate::println;
+
+/// File of the initial ramdisk, one per boot module.
+#[derive(Clone, Copy)]
+pub struct InitrdFile{
+    name: BootStr<64>,
+    // Virtual address of the content.
+    start: usize,
+    size: usize,
+}
+
+impl InitrdFile{
+    const fn empty() -> Self{
+        Self{ name: BootStr::new(), start: 0, size: 0 }
+    }
+
+    /// Name of the file, the first word of the module command line.
+    pub fn name(&self) -> &str{
+        self.name.as_str()
+    }
+
+    /// Size in bytes.
+    pub fn size(&self) -> usize{
+        self.size
+    }
+
+    /// Content of the file. Frames are reserved, so it lives forever.
+    pub fn data(&self) -> &'static [u8]{
+        if self.size == 0{
+            return &[];
+        }
+        unsafe{ from_raw_parts(self.start as *const u8, self.size) }
+    }
+}
+
+/// Initial ramdisk made of the boot modules.
+pub struct Initrd{
+    files: [InitrdFile; MAX_MODULES],
+    num_files: usize,
+}
+
+impl Initrd{
+    /// Create an empty ramdisk.
+    pub const fn empty() -> Self{
+        Self{ files: [InitrdFile::empty(); MAX_MODULES], num_files: 0 }
+    }
+
+    /// All files.
+    pub fn files(&self) -> &[InitrdFile]{
+        &self.files[..self.num_files]
+    }
+
+    /// Find a file by name.
+    pub fn find(&self, name: &str) -> Option<InitrdFile>{
+        self.files().iter().find(|file| file.name() == name).copied()
+    }
+}
+
+// Initial ramdisk of this boot.
+static INITRD: Mutex<Initrd> = Mutex::new(Initrd::empty());
+
+/// Build the initial ramdisk from boot modules. Module frames must have been
+/// reserved from the page allocator already.
+pub fn initrd_init(boot_info: &BootInfo){
+    let mut initrd = INITRD.lock();
+    initrd.num_files = 0;
+    for module in boot_info.modules(){
+        let name: &str = module.name.as_str().split_whitespace().next().unwrap_or("");
+        if name.is_empty(){
+            println!("[Err] Module without name at 0x{:x}, skip.", module.start);
+            continue;
+        }
+
+        let index: usize = initrd.num_files;
+        initrd.files[index] = InitrdFile{
+            name: BootStr::from_str(name),
+            start: phys_to_virt(PhysAddr::from(module.start)).to_usize(),
+            size: module.end - module.start,
+        };
+        initrd.num_files += 1;
+    }
+}
+
+/// Find a file of the initial ramdisk.
+pub fn initrd_find(name: &str) -> Option<&'static [u8]>{
+    INITRD.lock().find(name).map(|file| file.data())
+}
+
+/// Print all files of the initial ramdisk.
+pub fn initrd_info(){
+    let initrd = INITRD.lock();
+    println!("[+] Initrd: {} files", initrd.files().len());
+    for file in initrd.files(){
+        println!("[+]   {:<32} {:>10} bytes", file.name(), file.size());
+    }
+}
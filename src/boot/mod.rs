@@ -0,0 +1,6 @@
+pub mod boot_info;
+pub mod cmdline;
+pub mod efi;
+pub mod initrd;
+pub mod multiboot;
+pub mod pvh;
\ No newline at end of file
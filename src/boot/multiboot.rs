@@ -0,0 +1,134 @@
+#![allow(dead_code)]
+
+use multiboot2::{BootInformation, BootInformationHeader, FramebufferType, MemoryAreaType};
+
+use super::boot_info::{BootError, BootInfo, FramebufferInfo, FramebufferKind, MemAreaType};
+use super::efi::{efi_exit_boot_services, load_efi_mmap_tag};
+use crate::mm::page_table_entry::{PhysAddr, VirtAddr};
+use crate::mm::phys_page::{phys_to_virt, virt_to_phys};
+
+/// Magic value in %eax when booted by a multiboot2 loader.
+pub const MULTIBOOT2_MAGIC: u32 = 0x36d76289;
+
+/// Convert a multiboot2 memory area type.
+fn mem_area_type(area_type: MemoryAreaType) -> MemAreaType{
+    match area_type{
+        MemoryAreaType::Available => MemAreaType::Available,
+        MemoryAreaType::AcpiAvailable => MemAreaType::AcpiReclaimable,
+        MemoryAreaType::ReservedHibernate => MemAreaType::AcpiNvs,
+        MemoryAreaType::Defective => MemAreaType::Defective,
+        _ => MemAreaType::Reserved,
+    }
+}
+
+/// Fill boot information from a multiboot2 information structure at
+/// physical address multiboot_info.
+pub fn load_multiboot(info: &mut BootInfo, magic: u32, multiboot_info: usize) -> Result<(), BootError>{
+    if magic != MULTIBOOT2_MAGIC{
+        return Err(BootError::BadMagic(magic));
+    }
+
+    let header: VirtAddr = phys_to_virt(PhysAddr::from(multiboot_info));
+    let boot_info = unsafe{
+        BootInformation::load(header.to_usize() as *const BootInformationHeader)};
+    let mbi: BootInformation = match boot_info{
+        Ok(mbi) => mbi,
+        _ => { return Err(BootError::BadInfo); }
+    };
+    info.info_start = multiboot_info;
+    info.info_end = multiboot_info + mbi.total_size();
+
+    // Kernel ELF sections.
+    if let Some(sections) = mbi.elf_sections(){
+        for section in sections{
+            if !section.is_allocated(){
+                continue;
+            }
+            let name: &str = section.name().unwrap_or("");
+            info.add_elf_section(name, section.start_address() as usize,
+                section.end_address() as usize, section.flags().bits());
+        }
+    }
+
+    // Modules.
+    for module in mbi.module_tags(){
+        let name: &str = module.cmdline().unwrap_or("");
+        info.add_module(name, module.start_address() as usize, module.end_address() as usize);
+    }
+
+    // Command line and bootloader name.
+    if let Some(tag) = mbi.command_line_tag(){
+        if let Ok(cmdline) = tag.cmdline(){
+            info.cmdline.set(cmdline);
+        }
+    }
+    if let Some(tag) = mbi.boot_loader_name_tag(){
+        if let Ok(name) = tag.name(){
+            info.bootloader_name.set(name);
+        }
+    }
+
+    // Framebuffer.
+    if let Some(Ok(fb)) = mbi.framebuffer_tag(){
+        let kind: Option<FramebufferKind> = match fb.buffer_type(){
+            Ok(FramebufferType::RGB{ red, green, blue }) => {
+                Some(FramebufferKind::Rgb{
+                    red: (red.position, red.size),
+                    green: (green.position, green.size),
+                    blue: (blue.position, blue.size),
+                })
+            }
+            Ok(FramebufferType::Indexed{ .. }) => Some(FramebufferKind::Indexed),
+            Ok(FramebufferType::Text) => Some(FramebufferKind::Text),
+            _ => None,
+        };
+        if let Some(kind) = kind{
+            info.framebuffer = Some(FramebufferInfo{
+                addr: fb.address() as usize,
+                pitch: fb.pitch() as usize,
+                width: fb.width() as usize,
+                height: fb.height() as usize,
+                bpp: fb.bpp(),
+                kind: kind,
+            });
+        }
+    }
+
+    // ACPI RSDP, copied into the tag right after its 8-byte header.
+    if let Some(tag) = mbi.rsdp_v2_tag(){
+        let rsdp: PhysAddr = virt_to_phys(VirtAddr::from(tag as *const _ as *const u8 as usize + 8));
+        info.rsdp = Some((rsdp.to_usize(), 2));
+    }
+    else if let Some(tag) = mbi.rsdp_v1_tag(){
+        let rsdp: PhysAddr = virt_to_phys(VirtAddr::from(tag as *const _ as *const u8 as usize + 8));
+        info.rsdp = Some((rsdp.to_usize(), 0));
+    }
+
+    // EFI system table and image handle.
+    info.efi_system_table = mbi.efi_sdt64_tag().map(|tag| tag.sdt_address());
+    let efi_image_handle: Option<usize> = mbi.efi_ih64_tag().map(|tag| tag.image_handle());
+
+    // Memory map. Without the legacy memory map, use the EFI one. If GRUB
+    // left boot services running, the entry stub left them and kept the
+    // final EFI map.
+    if mbi.efi_bs_not_exited_tag().is_some(){
+        if info.efi_system_table.is_none() || efi_image_handle.is_none(){
+            return Err(BootError::BadInfo);
+        }
+        efi_exit_boot_services(info)?;
+    }
+    else if let Some(memory_map_tag) = mbi.memory_map_tag(){
+        for area in memory_map_tag.memory_areas(){
+            info.add_mem_area(area.start_address() as usize, area.end_address() as usize,
+                mem_area_type(MemoryAreaType::from(area.typ())));
+        }
+    }
+    else if let Some(tag) = mbi.efi_memory_map_tag(){
+        load_efi_mmap_tag(info, tag as *const _ as *const u8 as usize);
+    }
+    else{
+        return Err(BootError::NoMemoryMap);
+    }
+
+    Ok(())
+}
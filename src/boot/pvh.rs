@@ -0,0 +1,129 @@
+#![allow(dead_code)]
+
+use core::mem::size_of;
+use core::slice::from_raw_parts;
+use core::str::from_utf8;
+
+use super::boot_info::{BootError, BootInfo, MemAreaType};
+use crate::mm::page_table_entry::PhysAddr;
+use crate::mm::phys_page::phys_to_virt;
+
+/// Magic value in %eax when entered through the PVH entry point, also the
+/// first field of hvm_start_info.
+pub const PVH_MAGIC: u32 = 0x336ec578;
+
+/// Longest C string read from the start info.
+const PVH_MAX_STR: usize = 256;
+
+/// Start info passed in %ebx, see xen/include/public/arch-x86/hvm/start_info.h.
+#[repr(C)]
+struct HvmStartInfo{
+    magic: u32,
+    version: u32,
+    flags: u32,
+    nr_modules: u32,
+    modlist_paddr: u64,
+    cmdline_paddr: u64,
+    rsdp_paddr: u64,
+    // Version 1 and newer.
+    memmap_paddr: u64,
+    memmap_entries: u32,
+    reserved: u32,
+}
+
+/// Module entry of the start info.
+#[repr(C)]
+struct HvmModlistEntry{
+    paddr: u64,
+    size: u64,
+    cmdline_paddr: u64,
+    reserved: u64,
+}
+
+/// Memory map entry of the start info, types follow E820.
+#[repr(C)]
+struct HvmMemmapEntry{
+    addr: u64,
+    size: u64,
+    typ: u32,
+    reserved: u32,
+}
+
+/// Convert an E820 memory type.
+fn mem_area_type(typ: u32) -> MemAreaType{
+    match typ{
+        1 => MemAreaType::Available,
+        3 => MemAreaType::AcpiReclaimable,
+        4 => MemAreaType::AcpiNvs,
+        5 => MemAreaType::Defective,
+        _ => MemAreaType::Reserved,
+    }
+}
+
+/// Get a physical structure through the direct map.
+fn phys_ref<T>(paddr: u64) -> &'static T{
+    unsafe{ &*(phys_to_virt(PhysAddr::from(paddr as usize)).to_usize() as *const T) }
+}
+
+/// Get a physical array through the direct map.
+fn phys_slice<T>(paddr: u64, len: usize) -> &'static [T]{
+    unsafe{ from_raw_parts(phys_to_virt(PhysAddr::from(paddr as usize)).to_usize() as *const T, len) }
+}
+
+/// Read a NUL-terminated string at a physical address.
+fn phys_str(paddr: u64) -> &'static str{
+    if paddr == 0{
+        return "";
+    }
+    let bytes: &[u8] = phys_slice(paddr, PVH_MAX_STR);
+    let len: usize = bytes.iter().position(|b| *b == 0).unwrap_or(PVH_MAX_STR);
+    from_utf8(&bytes[..len]).unwrap_or("")
+}
+
+/// Fill boot information from a PVH start info at physical address start_info.
+pub fn load_pvh(info: &mut BootInfo, magic: u32, start_info: usize) -> Result<(), BootError>{
+    if magic != PVH_MAGIC{
+        return Err(BootError::BadMagic(magic));
+    }
+
+    let hvm: &HvmStartInfo = phys_ref(start_info as u64);
+    if hvm.magic != PVH_MAGIC{
+        return Err(BootError::BadInfo);
+    }
+    info.info_start = start_info;
+    info.info_end = start_info + size_of::<HvmStartInfo>();
+    info.bootloader_name.set("PVH");
+
+    // Memory map, only given since version 1.
+    if hvm.version < 1 || hvm.memmap_paddr == 0 || hvm.memmap_entries == 0{
+        return Err(BootError::NoMemoryMap);
+    }
+    let memmap: &[HvmMemmapEntry] = phys_slice(hvm.memmap_paddr, hvm.memmap_entries as usize);
+    for entry in memmap{
+        info.add_mem_area(entry.addr as usize, (entry.addr + entry.size) as usize, mem_area_type(entry.typ));
+    }
+
+    // Modules. One loaded without a command line, like qemu -initrd, is
+    // named initrd.
+    if hvm.modlist_paddr != 0{
+        let modules: &[HvmModlistEntry] = phys_slice(hvm.modlist_paddr, hvm.nr_modules as usize);
+        for module in modules{
+            let name: &str = match phys_str(module.cmdline_paddr){
+                "" => "initrd",
+                name => name,
+            };
+            info.add_module(name, module.paddr as usize, (module.paddr + module.size) as usize);
+        }
+    }
+
+    // Command line.
+    info.cmdline.set(phys_str(hvm.cmdline_paddr));
+
+    // ACPI RSDP, revision is at byte 15.
+    if hvm.rsdp_paddr != 0{
+        let revision: u8 = *phys_ref::<u8>(hvm.rsdp_paddr + 15);
+        info.rsdp = Some((hvm.rsdp_paddr as usize, revision));
+    }
+
+    Ok(())
+}
@@ -1,10 +1,12 @@
-extern crate multiboot2;
-use multiboot2::{Tag, TagType, FramebufferTag, FramebufferType};
-
 use super::fb::FrameBuffer;
 use super::fb_no_font::FrameBufferNoFont;
+use super::serial::{SerialPort, COM1};
+use crate::boot::boot_info::{FramebufferInfo, FramebufferKind};
+use crate::mm::page_table_entry::PhysAddr;
+use crate::mm::phys_page::{phys_to_virt, BOOT_MAPPED_LIMIT};
 
 use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
 use spin::Mutex;
 
 /// println macro
@@ -19,14 +21,53 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+/// Physical address and size of the VGA text buffer.
+pub const VGA_TEXT_BUFFER: usize = 0xb8000;
+pub const VGA_TEXT_WIDTH: usize = 80;
+pub const VGA_TEXT_HEIGHT: usize = 25;
+
+/// Screen behind the console.
+pub enum Screen{
+    // No screen known yet, or none at all.
+    None,
+    // Text mode, characters with attributes.
+    Text(FrameBufferNoFont),
+    // Linear framebuffer, glyphs drawn as pixels.
+    Graphic(FrameBuffer),
+}
+
+impl Screen{
+    /// Clear the whole screen.
+    pub fn clear(&mut self){
+        match self{
+            Screen::None => {}
+            Screen::Text(fb) => fb.clear(),
+            Screen::Graphic(fb) => fb.clean(),
+        }
+    }
+}
+
 lazy_static!{
-    pub static ref STDOUT: Mutex<FrameBufferNoFont> = Mutex::new(FrameBufferNoFont { 
-        _width: (80), _height: (25), _pos_x: (0), _pos_y: (0), _buffer: (0xb8000 as usize) });
+    pub static ref STDOUT: Mutex<Screen> = Mutex::new(Screen::None);
 }
 
 unsafe impl Send for STDOUT {}
 unsafe impl Sync for STDOUT {}
 
+lazy_static!{
+    pub static ref SERIAL: Mutex<SerialPort> = Mutex::new(SerialPort { _port: COM1 });
+}
+
+// Which outputs print goes to.
+static SCREEN_ENABLED: AtomicBool = AtomicBool::new(true);
+static SERIAL_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Choose console outputs.
+pub fn console_set_outputs(screen: bool, serial: bool){
+    SCREEN_ENABLED.store(screen, Ordering::Release);
+    SERIAL_ENABLED.store(serial, Ordering::Release);
+}
+
 /// Override format write for FrameBufferNoFont.
 impl fmt::Write for FrameBufferNoFont{
     fn write_str(&mut self, s: &str) -> fmt::Result{
@@ -35,49 +76,104 @@ impl fmt::Write for FrameBufferNoFont{
     }
 }
 
+/// Override format write for FrameBuffer.
+impl fmt::Write for FrameBuffer{
+    fn write_str(&mut self, s: &str) -> fmt::Result{
+        self.print_str(s);
+        Ok(())
+    }
+}
+
+/// Override format write for Screen.
+impl fmt::Write for Screen{
+    fn write_str(&mut self, s: &str) -> fmt::Result{
+        match self{
+            Screen::None => Ok(()),
+            Screen::Text(fb) => fb.write_str(s),
+            Screen::Graphic(fb) => fb.write_str(s),
+        }
+    }
+}
+
+/// Override format write for SerialPort.
+impl fmt::Write for SerialPort{
+    fn write_str(&mut self, s: &str) -> fmt::Result{
+        self.print_str(s);
+        Ok(())
+    }
+}
+
 /// Print function, provide for println! macro.
 pub fn _print(args: fmt::Arguments){
     use core::fmt::Write;
-    STDOUT.lock().write_fmt(args).unwrap();
+    if SCREEN_ENABLED.load(Ordering::Acquire){
+        STDOUT.lock().write_fmt(args).unwrap();
+    }
+    if SERIAL_ENABLED.load(Ordering::Acquire){
+        SERIAL.lock().write_fmt(args).unwrap();
+    }
 }
 
-/// Clear Screen.
-pub fn fb_init(){
-    STDOUT.lock().clear();
+/// Release the console locks, for fault handlers that interrupted a print.
+pub fn console_force_unlock(){
+    unsafe{
+        STDOUT.force_unlock();
+        SERIAL.force_unlock();
+    }
 }
 
-#[repr(C)]
-pub struct MultibootInfo{
-    pub total_size: u32,
-    pub pad: u32,
+/// Pixel value of white in a framebuffer.
+fn white(kind: FramebufferKind) -> u32{
+    match kind{
+        FramebufferKind::Rgb{ red, green, blue } => {
+            let mut color: u32 = 0;
+            for (position, size) in [red, green, blue]{
+                color |= ((1u32 << size) - 1) << position;
+            }
+            color
+        }
+        // Entry 15 is white in the default VGA palette.
+        _ => 15,
+    }
 }
 
-/// Find frame buffer from multiboot structure.
-pub fn find_fb(info: *mut MultibootInfo)->Option<*mut u8>{
-    unsafe{
-        let mut curr: Tag = *(info.add(1) as *const Tag);
-        while curr.typ() != TagType::End{
-
-            if curr.typ() == TagType::Framebuffer{
-                let mut fb: &FramebufferTag = curr.cast_tag::<FramebufferTag>();
-                let buf_type = fb.buffer_type();
-                match buf_type{
-                    Ok(_) => {
-                        if fb.bpp() == 32 && fb.width() == 800 && fb.height() == 600 && fb.pitch() == 3200{
-                            return Some(fb.address() as *mut u8);
-                        }
-                    }
-                    Err(_) => {
-                        return None;
-                    }
-                }
+/// Screen on the framebuffer set up by the bootloader.
+fn framebuffer_screen(info: &FramebufferInfo) -> Screen{
+    if info.addr + info.pitch * info.height > BOOT_MAPPED_LIMIT{
+        return Screen::None;
+    }
 
-            }
+    let buffer: usize = phys_to_virt(PhysAddr::from(info.addr)).to_usize();
+    match info.kind{
+        FramebufferKind::Text => {
+            Screen::Text(FrameBufferNoFont::new(buffer, info.width, info.height))
+        }
+        _ => {
+            Screen::Graphic(FrameBuffer::new(buffer, info.width, info.height, info.pitch,
+                info.bpp as usize, white(info.kind)))
+        }
+    }
+}
 
-            let next: *const Tag = &curr;
-            let next_offset = ((curr.size + 7) &!7) as usize;
-            curr = *((next as *const u8).add(next_offset) as *const Tag);
+/// Set up the console on the bootloader framebuffer. Without one, fall back
+/// to the VGA text buffer if the machine has it (BIOS boot), or else to the
+/// serial port only.
+pub fn console_init(fb: Option<FramebufferInfo>, legacy_vga: bool){
+    SERIAL.lock().init();
+
+    let screen: Screen = match fb{
+        Some(info) => framebuffer_screen(&info),
+        _ if legacy_vga => {
+            Screen::Text(FrameBufferNoFont::new(phys_to_virt(PhysAddr::from(VGA_TEXT_BUFFER)).to_usize(),
+                VGA_TEXT_WIDTH, VGA_TEXT_HEIGHT))
         }
-        return None;
+        _ => Screen::None,
+    };
+    if let Screen::None = screen{
+        console_set_outputs(false, true);
     }
+
+    let mut stdout = STDOUT.lock();
+    *stdout = screen;
+    stdout.clear();
 }
\ No newline at end of file
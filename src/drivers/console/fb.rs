@@ -1,8 +1,23 @@
+#![allow(dead_code)]
+
+use core::ptr::{copy, write_bytes};
+
 use super::font::_ASCII_FONT;
 
+/// Width and height of a glyph in _ASCII_FONT.
+pub const FONT_WIDTH: usize = 8;
+pub const FONT_HEIGHT: usize = 8;
+
+/// Linear framebuffer console, draws glyphs of _ASCII_FONT.
 #[derive(Debug, Clone, Copy)]
 pub struct FrameBuffer{
+    // Size in pixels.
     pub _width: usize,
+    pub _height: usize,
+    // Bytes per line.
+    pub _pitch: usize,
+    // Bits per pixel.
+    pub _bpp: usize,
     pub _font_width: usize,
     pub _font_height: usize,
 
@@ -11,67 +26,87 @@ pub struct FrameBuffer{
     pub _max_x: usize,
     pub _max_y: usize,
 
+    // Pixel value of the foreground, the background is always 0.
+    pub _color: u32,
     pub _buffer: usize,
 }
 
 impl FrameBuffer{
-    /// Convert buffer to *mut u32
-    pub fn buffer_to_ptr(&self) -> *mut u32{
-        self._buffer as *mut u32
+    /// Create a console on a framebuffer of any size and depth.
+    pub fn new(buffer: usize, width: usize, height: usize, pitch: usize, bpp: usize, color: u32) -> Self{
+        Self{
+            _width: width,
+            _height: height,
+            _pitch: pitch,
+            _bpp: bpp,
+            _font_width: FONT_WIDTH,
+            _font_height: FONT_HEIGHT,
+            _pos_x: 0,
+            _pos_y: 0,
+            _max_x: width / FONT_WIDTH,
+            _max_y: height / FONT_HEIGHT,
+            _color: color,
+            _buffer: buffer,
+        }
+    }
+
+    /// Convert buffer to *mut u8
+    pub fn buffer_to_ptr(&self) -> *mut u8{
+        self._buffer as *mut u8
+    }
+
+    /// Bytes per pixel.
+    fn pixel_bytes(&self) -> usize{
+        (self._bpp + 7) / 8
+    }
+
+    /// Write a pixel value at (x, y).
+    fn put_pixel(&mut self, x: usize, y: usize, color: u32){
+        let offset: usize = y * self._pitch + x * self.pixel_bytes();
+        unsafe{
+            let ptr: *mut u8 = self.buffer_to_ptr().add(offset);
+            match self.pixel_bytes(){
+                4 => { (ptr as *mut u32).write_volatile(color); }
+                3 => {
+                    ptr.write_volatile(color as u8);
+                    ptr.add(1).write_volatile((color >> 8) as u8);
+                    ptr.add(2).write_volatile((color >> 16) as u8);
+                }
+                2 => { (ptr as *mut u16).write_volatile(color as u16); }
+                _ => { ptr.write_volatile(color as u8); }
+            }
+        }
     }
 
     /// Clean all frameBuffer.
     pub fn clean(&mut self){
-        let buffer_size: usize = self._max_x * self._font_width * self._max_y * self._font_height;
-        for i in 0..buffer_size{
-            unsafe{ self.buffer_to_ptr().add(i as usize).write(0x00000000); }
-        }
+        unsafe{ write_bytes(self.buffer_to_ptr(), 0, self._pitch * self._height); }
+        self._pos_x = 0;
+        self._pos_y = 0;
     }
 
     /// Set frameBuffer base.
-    pub fn set_base(&mut self, buffer: *mut u32){
-        unsafe{self._buffer = buffer as usize;}
+    pub fn set_base(&mut self, buffer: *mut u8){
+        self._buffer = buffer as usize;
     }
 
     /// Scroll up frameBuffer.
     pub fn scroll_up(&mut self){
+        let row: usize = self._pitch * self._font_height;
+        let count: usize = row * (self._max_y - 1);
 
-        let mut count: usize = self._width * ((self._max_y - 1) * self._font_height);
-
-        let mut cur: usize = 0;
-        let mut row: usize = self._width * self._font_height;
-
-        // Move the text up one row
-        loop{
-            unsafe{
-                let content: u32 = *self.buffer_to_ptr().add(cur + row);
-                self.buffer_to_ptr().add(cur).write(content);
-            }
-            cur += 1;
-
-            count -= 1;
-            if count == 0{
-                break;
-            }
-        }
-
-        // Clean up the last row
-        loop{
-            unsafe{
-                self.buffer_to_ptr().add(cur).write(0x00000000);
-            }
-
-            cur += 1;
-
-            row -= 1;
-            if row == 0{
-                break;
-            }
+        // Move the text up one row, then clean up the last row.
+        unsafe{
+            copy(self.buffer_to_ptr().add(row), self.buffer_to_ptr(), count);
+            write_bytes(self.buffer_to_ptr().add(count), 0, row);
         }
     }
-    
+
     /// Output a single character on frameBuffer.
     pub fn output(&mut self, ch: u8){
+        if self._max_x == 0 || self._max_y == 0{
+            return ;
+        }
 
         let mut mch: u8 = ch;
         if (mch as i8) <= 0{
@@ -95,23 +130,20 @@ impl FrameBuffer{
             return ;
         }
 
-        let index: usize = (ch as usize) * (self._font_width * self._font_height / 8);
-        unsafe{
-            let ptr: *mut u8 = ((_ASCII_FONT as *const u8).add(index)) as *mut u8;
-            let mut cur: usize = self._pos_x * self._font_width + (self._pos_y * self._font_height) * self._width;
-            for j in 0..self._font_height{
-                let mut bitmap: i8 = (*ptr.add(j)) as i8;
-                for i in 0..self._font_width{
-                    let color: i32 = (bitmap >> 7) as i32;
-                    self.buffer_to_ptr().add(cur + i).write(color as u32);
-                    bitmap <<= 1;
-                }
-                cur += self._width;
+        let index: usize = (mch as usize) * (self._font_width * self._font_height / 8);
+        let x: usize = self._pos_x * self._font_width;
+        let y: usize = self._pos_y * self._font_height;
+        for j in 0..self._font_height{
+            let mut bitmap: u8 = _ASCII_FONT[index + j];
+            for i in 0..self._font_width{
+                let color: u32 = if bitmap & 0x80 != 0 { self._color } else { 0 };
+                self.put_pixel(x + i, y + j, color);
+                bitmap <<= 1;
             }
-            self._pos_x += 1;
         }
+        self._pos_x += 1;
     }
-    
+
     /// Print a string to frameBuffer.
     pub fn print_str(&mut self, s: &str){
         for ch in s.bytes(){
@@ -119,4 +151,4 @@ impl FrameBuffer{
         }
     }
 
-}
\ No newline at end of file
+}
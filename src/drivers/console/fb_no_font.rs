@@ -34,6 +34,10 @@ impl CharByte{
 }
 
 impl FrameBufferNoFont{
+    /// Create a console on a text mode buffer of width x height characters.
+    pub fn new(buffer: usize, width: usize, height: usize) -> Self{
+        Self{ _width: width, _height: height, _pos_x: 0, _pos_y: 0, _buffer: buffer }
+    }
 
     /// Clear frame buffer.
     pub fn clear(&mut self){
@@ -42,13 +46,36 @@ impl FrameBufferNoFont{
         for i in 0..buffer_size{
             buffer[i] = CharByte::default(0);
         }
+        self._pos_x = 0;
+        self._pos_y = 0;
+    }
+
+    /// Scroll up one line.
+    pub fn scroll_up(&mut self){
+        let buffer_size: usize = self._width * self._height;
+        let buffer = unsafe{from_raw_parts_mut(self._buffer as *mut CharByte, buffer_size)};
+        for i in self._width..buffer_size{
+            buffer[i - self._width] = CharByte::set(buffer[i]._char, buffer[i]._color);
+        }
+        for i in buffer_size - self._width..buffer_size{
+            buffer[i] = CharByte::default(0);
+        }
     }
 
     /// Print a character to frame buffer.
     pub fn output(&mut self, ch: u8){
+        if self._width == 0 || self._height == 0{
+            return ;
+        }
+
         if ch == b'\n' || self._pos_x == self._width{
-            self._pos_x += 1;
-            self._pos_y = 0;
+            self._pos_x = 0;
+            self._pos_y += 1;
+        }
+
+        if self._pos_y == self._height{
+            self._pos_y -= 1;
+            self.scroll_up();
         }
 
         if ch == b'\n'{
@@ -58,10 +85,10 @@ impl FrameBufferNoFont{
         let buffer_size: usize = self._width * self._height;
         let buffer = unsafe{from_raw_parts_mut(self._buffer as *mut CharByte, buffer_size)};
 
-        let pos: usize = self._pos_x * self._width + self._pos_y;
+        let pos: usize = self._pos_y * self._width + self._pos_x;
         buffer[pos] = CharByte::default(ch);
 
-        self._pos_y += 1;
+        self._pos_x += 1;
     }
 
     /// Print a string to frame buffer.
@@ -1,4 +1,5 @@
 pub mod console;
 pub mod font;
 pub mod fb;
-pub mod fb_no_font;
\ No newline at end of file
+pub mod fb_no_font;
+pub mod serial;
\ No newline at end of file
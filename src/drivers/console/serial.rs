@@ -0,0 +1,45 @@
+#![allow(dead_code)]
+
+use crate::asms::io::{inb, outb};
+
+/// I/O port of the first serial port.
+pub const COM1: u16 = 0x3f8;
+
+/// 16550 UART serial port.
+pub struct SerialPort{
+    pub _port: u16,
+}
+
+impl SerialPort{
+    /// Initialize as 115200 baud, 8N1, FIFO enabled.
+    pub fn init(&mut self){
+        outb(self._port + 1, 0x00);    // Disable interrupts
+        outb(self._port + 3, 0x80);    // Enable DLAB to set baud rate divisor
+        outb(self._port + 0, 0x01);    // Divisor low byte (115200 baud)
+        outb(self._port + 1, 0x00);    // Divisor high byte
+        outb(self._port + 3, 0x03);    // 8 bits, no parity, one stop bit
+        outb(self._port + 2, 0xc7);    // Enable and clear FIFO, 14-byte threshold
+        outb(self._port + 4, 0x03);    // DTR and RTS
+    }
+
+    /// Check whether the transmitter can take another byte.
+    fn is_transmit_empty(&self) -> bool{
+        inb(self._port + 5) & 0x20 != 0
+    }
+
+    /// Send a byte.
+    pub fn output(&mut self, ch: u8){
+        if ch == b'\n'{
+            self.output(b'\r');
+        }
+        while !self.is_transmit_empty(){}
+        outb(self._port, ch);
+    }
+
+    /// Send a string.
+    pub fn print_str(&mut self, s: &str){
+        for ch in s.bytes(){
+            self.output(ch);
+        }
+    }
+}
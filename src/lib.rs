@@ -1,10 +1,16 @@
 // Remove standard library, since we are writing our own OS.
 #![feature(lang_items)]
+#![feature(alloc_error_handler)]
+#![feature(abi_x86_interrupt)]
 #![no_std]
 
 #[macro_use]
 extern crate lazy_static;
 extern crate multiboot2;
+extern crate alloc;
+
+/// Boot information
+mod boot;
 
 /// Drivers
 mod drivers;
@@ -19,38 +25,156 @@ mod asms;
 mod utils;
 
 
-use drivers::console::console::{MultibootInfo, fb_init};
+use boot::boot_info::BOOT_INFO;
+use boot::cmdline::{cmdline_init, kernel_options, ConsoleKind, SELFTEST_ALLOC, SELFTEST_HEAP, SELFTEST_PAGING,
+    SELFTEST_STACK};
+use boot::initrd::{initrd_find, initrd_info, initrd_init};
+use boot::multiboot::load_multiboot;
+use boot::pvh::{load_pvh, PVH_MAGIC};
+use drivers::console::console::{console_init, console_set_outputs};
 
-use mm::page_table_entry::{VirtAddr, PhysAddr, PTEFlags};
-use mm::phys_page::{kernel_heap_init, phys_page_alloc, phys_page_free};
-use mm::page_table::{kernel_phys_to_virt, identical_phys_to_virt, PageTable};
-use mm::layout::{find_kernel_areas};
+use mm::page_table_entry::{VirtAddr, PhysAddr, PTEFlags, NO_EXECUTE, PRESENT};
+use mm::phys_page::{phys_page_alloc, phys_page_free, phys_pages_alloc, phys_pages_free};
+use mm::page_table::{identical_phys_to_virt, PageTable, HUGE_PAGE_SIZE_2M};
+use mm::layout::{find_kernel_areas, kernel_layout_init};
+use mm::kstack::{kstack_alloc, kstack_switch, KSTACK_IST_SIZE};
+use mm::frame::{frames_of_type, page_of, PageType};
+use mm::heap::kernel_heap_init;
+use mm::slab::slab_info;
+use mm::meminfo::print_meminfo;
+use mm::address_space::{address_space_switch, AddressSpace, FaultAccess, VmaKind, PROT_READ, PROT_WRITE};
+use mm::fault::with_user_access;
 
-use core::panic::PanicInfo;
+use asms::gdt::{gdt_init, tss_set_kernel_stack};
+use asms::idt::idt_init;
 
-// use asms::idt::{IDT64};
-// use asms::idt::divide_by_zero_handler;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use core::hint::black_box;
+use core::panic::PanicInfo;
 
 /// This is the main entry point of the kernel.
 #[no_mangle]
-pub extern "C" fn kernel_start(multiboot_info: usize, free_mem_base: *mut u8){
-    // Setup frame buffer.
-    fb_init();
+pub extern "C" fn kernel_start(boot_magic: u32, boot_info_addr: usize){
+    // Collect boot information. The console is not set up yet, errors are
+    // reported afterwards.
+    let mut boot_info = BOOT_INFO.lock();
+    let loaded = match boot_magic{
+        PVH_MAGIC => load_pvh(&mut boot_info, boot_magic, boot_info_addr),
+        _ => load_multiboot(&mut boot_info, boot_magic, boot_info_addr),
+    };
+
+    // Setup console. There is no VGA text buffer under UEFI.
+    console_init(boot_info.framebuffer, boot_info.efi_system_table.is_none());
 
     println!("[+] Hello world! This is micro rust os.\n");
 
-    // Setup kernel heap. Enable physical page allocation.
-    println!("[+] Setup kernel heap.");
-    println!("[+] Mem base: {:x}", free_mem_base as usize);
-    let heap_base = PhysAddr::from(free_mem_base);
-    kernel_heap_init(&heap_base);
+    match loaded{
+        Ok(_) => {
+            boot_info.print();
+        }
+        Err(err) => {
+            println!("[Err] Invalid boot information: {:?}", err);
+            return ;
+        }
+    }
+
+    // Parse kernel command line.
+    cmdline_init(boot_info.cmdline.as_str());
+    let options = kernel_options();
+    match options.console{
+        ConsoleKind::Screen => console_set_outputs(true, false),
+        ConsoleKind::Serial => console_set_outputs(false, true),
+        ConsoleKind::Both => console_set_outputs(true, true),
+    }
+    options.print();
+
+    // Find usable memory. Enable physical page allocation.
+    println!("\n[+] Mapping kernel memory areas.");
+    find_kernel_areas(&boot_info);
+
+    // Switch to the kernel page table with a direct map of all memory.
+    kernel_layout_init(&boot_info);
+    print_meminfo();
+    drop(boot_info);
+
+    // Kernel stacks sit above an unmapped guard page. An overflow faults
+    // there, and the double fault is handled on a stack of its own.
+    let double_fault_stack = match kstack_alloc(KSTACK_IST_SIZE){
+        Some(stack) => stack,
+        _ => {
+            println!("[Err] Failed allocate double fault stack.");
+            return ;
+        }
+    };
+    let stack = match kstack_alloc(options.kstack_size){
+        Some(stack) => stack,
+        _ => {
+            println!("[Err] Failed allocate kernel stack.");
+            return ;
+        }
+    };
+    gdt_init(double_fault_stack.top());
+    tss_set_kernel_stack(stack.top());
+    idt_init();
+    println!("[+] Switch to kernel stack: {:x}-{:x}", stack.bottom(), stack.top());
+    kstack_switch(&stack, kernel_main);
+}
+
+/// Rest of the kernel start, on a guarded kernel stack.
+extern "C" fn kernel_main() -> !{
+    let options = kernel_options();
+
+    // Boot modules become the initial ramdisk.
+    initrd_init(&BOOT_INFO.lock());
+    initrd_info();
+    if let Some(motd) = initrd_find("/etc/motd"){
+        print!("{}", core::str::from_utf8(motd).unwrap_or(""));
+    }
 
     // Test allocate physical page.
+    if options.selftest(SELFTEST_ALLOC){
+        selftest_alloc();
+    }
+
+    // Setup kernel heap. Enable alloc crate.
+    println!("[+] Setup kernel heap.");
+    kernel_heap_init();
+
+    // Test kernel heap.
+    if options.selftest(SELFTEST_HEAP){
+        selftest_heap();
+    }
+
+    // Test paging.
+    if options.selftest(SELFTEST_PAGING){
+        selftest_paging();
+        selftest_address_space();
+    }
+
+    // Print slab caches and memory usage.
+    println!("");
+    slab_info();
+    print_meminfo();
+
+    // Overflow the kernel stack, ends in the double fault handler.
+    if options.selftest(SELFTEST_STACK){
+        println!("[+] Overflow kernel stack.");
+        selftest_stack(0);
+    }
+
+    loop{}
+}
+
+/// Allocate and free physical pages.
+fn selftest_alloc(){
     for i in 0..4{
         let frame = phys_page_alloc();
         match frame{
             Some(phys_page) => {
-                println!("[+] Allocate frame: {:x}", phys_page.to_usize());
+                let page_type = page_of(phys_page).map(|page| page.page_type());
+                println!("[+] Allocate frame: {:x}, type: {:?}", phys_page.to_usize(), page_type);
                 if i < 2{
                     phys_page_free(phys_page);
                     println!("[+] Free frame.");
@@ -63,7 +187,31 @@ pub extern "C" fn kernel_start(multiboot_info: usize, free_mem_base: *mut u8){
         }
     }
 
-    // Test paging. We map from 0x300000 to 0x10300000.
+    // Test allocate contiguous physical pages (2MB).
+    match phys_pages_alloc(9){
+        Some(block) => {
+            println!("[+] Allocate 2MB block: {:x}", block.to_usize());
+            phys_pages_free(block, 9);
+        }
+        _ => {
+            println!("[Err] Failed allocate 2MB block.");
+        }
+    }
+}
+
+/// Allocate from the kernel heap.
+fn selftest_heap(){
+    let boxed: Box<usize> = Box::new(0x1234);
+    let mut vector: Vec<usize> = Vec::new();
+    for i in 0..1024{
+        vector.push(i);
+    }
+    println!("[+] Box: {:x}, Vec: {} items at {:x}", *boxed, vector.len(), vector.as_ptr() as usize);
+}
+
+/// Map fresh frames in a new page table, check that bad mappings are
+/// refused, and tear the table down.
+fn selftest_paging(){
     println!("\n[+] Enable paging.");
     let create_page_table = PageTable::new();
     match create_page_table{
@@ -72,33 +220,145 @@ pub extern "C" fn kernel_start(multiboot_info: usize, free_mem_base: *mut u8){
 
             let fb_paddr: PhysAddr = PhysAddr::from(0xb8000);
             let fb_vaddr: VirtAddr = identical_phys_to_virt(fb_paddr);
-            new_table.map(fb_vaddr, fb_paddr, PTEFlags::new_kern_flags());
+            if let Err(err) = new_table.map(fb_vaddr, fb_paddr, PTEFlags::new_kern_flags()){
+                println!("[Err] Failed map framebuffer: {:?}", err);
+            }
 
-            let paddr: PhysAddr = PhysAddr::from(free_mem_base as usize + 0x100000);
-            let vaddr: VirtAddr = kernel_phys_to_virt(paddr);
-            new_table.map(vaddr, paddr, PTEFlags::new_kern_flags());
+            let paddr: PhysAddr = phys_page_alloc().expect("Test frame.");
+            let vaddr: VirtAddr = VirtAddr::from(0x40000000);
+            if let Err(err) = new_table.map(vaddr, paddr, PTEFlags::new_kern_flags()){
+                println!("[Err] Failed map frame: {:?}", err);
+            }
+
+            if let Some((mapped_paddr, flags)) = new_table.translate(vaddr){
+                println!("[+] Virtual: {:x} to Physical: {:x}, flags: {:x}", vaddr.to_usize(),
+                    mapped_paddr.to_usize(), flags.as_u64());
+            }
 
-            //let curr_page_table: PageTable = new_table.swap();
+            // Read-only and no-execute, then back.
+            let readonly: PTEFlags = PTEFlags::new(PRESENT | NO_EXECUTE);
+            if new_table.protect(vaddr, readonly).is_ok(){
+                if let Some(flags) = new_table.query(vaddr){
+                    println!("[+] Protect: {}", flags);
+                }
+                let _ = new_table.protect(vaddr, PTEFlags::new_kern_flags());
+            }
 
-            let mapped_paddr: PhysAddr = new_table.retrieve(vaddr);
-            println!("[+] Virtual: {:x} to Physical: {:x}", vaddr.to_usize(), mapped_paddr.to_usize());
+            println!("[+] Map again: {:?}, misaligned: {:?}, non-canonical: {:?}",
+                new_table.map(vaddr, paddr, PTEFlags::new_kern_flags()),
+                new_table.map(vaddr + 1, paddr, PTEFlags::new_kern_flags()),
+                new_table.map(VirtAddr::from(0x0000800000000000), paddr, PTEFlags::new_kern_flags()));
+
+            match new_table.unmap(vaddr){
+                Some(frame) => {
+                    println!("[+] Unmap frame: {:x}", frame.to_usize());
+                    phys_page_free(frame);
+                }
+                _ => {
+                    println!("[Err] Failed unmap frame.");
+                }
+            }
 
-            //curr_page_table.enable();
+            // A 2MB block is mapped with one huge page, and split when half
+            // of it goes away.
+            if let Some(block) = phys_pages_alloc(9){
+                let huge_vaddr: VirtAddr = VirtAddr::from(0x40200000);
+                let size: usize = HUGE_PAGE_SIZE_2M;
+                if new_table.map_region(huge_vaddr, block, size, PTEFlags::new_kern_flags()).is_ok(){
+                    let before = new_table.page_size(huge_vaddr);
+                    let _ = new_table.unmap_region(huge_vaddr + size / 2, size / 2);
+                    println!("[+] Huge page: {:?}, after split: {:?}, upper half: {:?}", before,
+                        new_table.page_size(huge_vaddr), new_table.translate(huge_vaddr + size / 2).is_some());
+                    let _ = new_table.unmap_region(huge_vaddr, size / 2);
+                }
+                phys_pages_free(block, 9);
+            }
+
+            new_table.dump();
+
+            // Dropping the table frees its tables, and the frames it owns.
+            if let Some(frame) = phys_page_alloc(){
+                if new_table.map(vaddr, frame, PTEFlags::new_kern_flags()).is_err(){
+                    phys_page_free(frame);
+                }
+            }
+            new_table.set_owns_frames(true);
+            let tables: usize = frames_of_type(PageType::PageTable);
+            drop(new_table);
+            println!("[+] Drop page table, table frames: {} -> {}", tables, frames_of_type(PageType::PageTable));
         }
         _ => {
             println!("[Err] Failed allocate page table.");
         }
     }
+}
 
-    println!("\n[+] Mapping kernel memory areas.");
-    find_kernel_areas(multiboot_info);
+/// Map, protect and unmap regions in a new address space.
+fn selftest_address_space(){
+    let mut space = match AddressSpace::new(){
+        Some(space) => space,
+        _ => {
+            println!("[Err] Failed create address space.");
+            return ;
+        }
+    };
+    let frames: usize = frames_of_type(PageType::Anon);
+    let anon = space.mmap(None, 0x4000, PROT_READ | PROT_WRITE, VmaKind::Anon);
+    let stack = space.mmap(None, 0x2000, PROT_READ | PROT_WRITE, VmaKind::Stack{ guard: true });
+    println!("[+] Mmap anon: {:x?}, stack: {:x?}", anon, stack);
+    if let Ok(addr) = stack{
+        // Only the lower piece of a split stack holds the guard.
+        println!("[+] Split stack: {:?}, guard fault: {:?}, fault above: {:?}",
+            space.mprotect(addr, 0x1000, PROT_READ),
+            space.handle_fault(addr - 0x1000, FaultAccess::Read),
+            space.handle_fault(addr, FaultAccess::Read));
+    }
+    if let Ok(addr) = anon{
+        println!("[+] Mprotect: {:?}, write fault: {:?}, munmap: {:?}",
+            space.mprotect(addr + 0x1000, 0x1000, PROT_READ),
+            space.handle_fault(addr + 0x1000, FaultAccess::Write),
+            space.munmap(addr + 0x2000, 0x1000));
+    }
 
-    // Setup interrupt descriptor table.
-    // let idt: IDT64 = IDT64::new();
-    // idt.default_setup();
-    // println!("[+] Enable interruptions.");
+    // Anonymous pages get a zeroed frame on first touch.
+    if let Ok(addr) = anon{
+        let before: usize = frames_of_type(PageType::Anon);
+        address_space_switch(Some(space));
+        let value: usize = with_user_access(|| unsafe{
+            let value: usize = core::ptr::read_volatile(addr as *const usize);
+            core::ptr::write_volatile((addr + 0x3000) as *mut usize, 0x1234);
+            value
+        });
+        space = address_space_switch(None).expect("Test address space.");
+        println!("[+] Demand zero: read {:x}, anon frames: {} -> {}", value, before,
+            frames_of_type(PageType::Anon));
+    }
 
-    loop{}
+    // A clone shares frames until one side writes.
+    if let (Ok(addr), Ok(child)) = (anon, space.clone_cow()){
+        let shared: usize = frames_of_type(PageType::Anon);
+        address_space_switch(Some(space));
+        with_user_access(|| unsafe{ core::ptr::write_volatile((addr + 0x3000) as *mut usize, 0x5678) });
+        space = address_space_switch(Some(child)).expect("Test address space.");
+        let value: usize = with_user_access(|| unsafe{ core::ptr::read_volatile((addr + 0x3000) as *const usize) });
+        let child = address_space_switch(None).expect("Test address space.");
+        println!("[+] Copy on write: child read {:x}, anon frames: {} -> {}", value, shared,
+            frames_of_type(PageType::Anon));
+        drop(child);
+    }
+    space.print();
+    drop(space);
+    println!("[+] Drop address space, anon frames: {} -> {}", frames, frames_of_type(PageType::Anon));
+}
+
+/// Recurse until the stack runs into its guard page.
+fn selftest_stack(depth: usize) -> usize{
+    let frame: [usize; 64] = [depth; 64];
+    black_box(&frame);
+    if depth == usize::MAX{
+        return 0;
+    }
+    selftest_stack(depth + 1) + frame[0]
 }
 
 /// Stack unwinding.
@@ -0,0 +1,625 @@
+#![allow(dead_code)]
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::ptr::copy_nonoverlapping;
+use spin::Mutex;
+
+use crate::println;
+
+use super::frame::PageType;
+use super::layout::KERNEL_LAYOUT;
+use super::page_table::{MapError, PageSize, PageTable};
+use super::page_table_entry::{PhysAddr, PTEFlags, VirtAddr, PTE, NO_CACHE, NO_EXECUTE, PRESENT, USER, WRITABLE,
+    WRITE_THROUGH};
+use super::page_walk::Mapping;
+use super::phys_page::{phys_page_get, phys_page_put, phys_page_refcount, phys_pages_alloc_typed, phys_to_virt, set_frame,
+    PAGE_SIZE};
+
+/// Region permissions, as for mmap.
+pub const PROT_NONE: u32 =  0;
+pub const PROT_READ: u32 =  1;
+pub const PROT_WRITE: u32 = 1 << 1;
+pub const PROT_EXEC: u32 =  1 << 2;
+
+/// All known permission bits.
+pub const PROT_MASK: u32 =  PROT_READ | PROT_WRITE | PROT_EXEC;
+
+/// User half of the address space handed out by mmap.
+pub const USER_SPACE_START: usize = 0x1000;
+pub const USER_SPACE_END: usize =   0x0000_8000_0000_0000;
+/// Where mmap starts looking for room when no address is given.
+pub const MMAP_BASE: usize =        0x0000_1000_0000_0000;
+
+/// How a region is backed.
+#[derive(Clone, Copy)]
+pub enum VmaKind{
+    // Private zero-filled memory.
+    Anon,
+    // Device memory at a physical address, mapped uncached.
+    Device(PhysAddr),
+    // Memory whose frames are shared by every copy of the address space.
+    Shared,
+    // Private zero-filled memory growing down. With guard, the lowest page
+    // is a guard and is never mapped; only the lowest piece of a split
+    // stack keeps it.
+    Stack{ guard: bool },
+    // Private copy of file contents from offset on, zero past its end.
+    File{ data: &'static [u8], offset: usize },
+}
+
+/// Virtual memory area [start, end).
+#[derive(Clone, Copy)]
+pub struct Vma{
+    pub start: usize,
+    pub end: usize,
+    pub prot: u32,
+    pub kind: VmaKind,
+}
+
+impl Vma{
+    /// Size in bytes.
+    pub fn size(&self) -> usize{
+        self.end - self.start
+    }
+
+    /// Check whether addr is inside.
+    pub fn contains(&self, addr: usize) -> bool{
+        addr >= self.start && addr < self.end
+    }
+
+    /// Page flags for the region permissions.
+    pub fn pte_flags(&self) -> PTEFlags{
+        let mut flags: u64 = PRESENT | USER;
+        if self.prot & PROT_WRITE != 0{
+            flags |= WRITABLE;
+        }
+        if self.prot & PROT_EXEC == 0{
+            flags |= NO_EXECUTE;
+        }
+        if let VmaKind::Device(_) = self.kind{
+            flags |= WRITE_THROUGH | NO_CACHE;
+        }
+        PTEFlags::new(flags)
+    }
+
+    /// Check whether pages are only backed when first touched.
+    pub fn is_lazy(&self) -> bool{
+        match self.kind{
+            VmaKind::Anon | VmaKind::Stack{ .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Check whether the region allows an access.
+    pub fn allows(&self, access: FaultAccess) -> bool{
+        match access{
+            FaultAccess::Read => self.prot != PROT_NONE,
+            FaultAccess::Write => self.prot & PROT_WRITE != 0,
+            FaultAccess::Execute => self.prot & PROT_EXEC != 0,
+        }
+    }
+
+    /// Check whether writes to the region stay private to one address space.
+    /// Frames of a writable private region shared after a clone are mapped
+    /// read-only and copied on the first write.
+    pub fn is_private(&self) -> bool{
+        match self.kind{
+            VmaKind::Device(_) | VmaKind::Shared => false,
+            _ => true,
+        }
+    }
+
+    /// Check whether the region owns the frames mapped in it.
+    pub fn owns_frames(&self) -> bool{
+        match self.kind{
+            VmaKind::Device(_) => false,
+            _ => true,
+        }
+    }
+
+    /// Split at addr, into [start, addr) and [addr, end).
+    fn split(&self, addr: usize) -> (Vma, Vma){
+        let mut low: Vma = *self;
+        let mut high: Vma = *self;
+        low.end = addr;
+        high.start = addr;
+        match self.kind{
+            VmaKind::Device(paddr) => {
+                high.kind = VmaKind::Device(paddr + (addr - self.start));
+            }
+            VmaKind::File{ data, offset } => {
+                high.kind = VmaKind::File{ data: data, offset: offset + (addr - self.start) };
+            }
+            VmaKind::Stack{ .. } => {
+                high.kind = VmaKind::Stack{ guard: false };
+            }
+            _ => {}
+        }
+        (low, high)
+    }
+
+    /// Name of the backing, for dumps.
+    fn kind_name(&self) -> &'static str{
+        match self.kind{
+            VmaKind::Anon => "anon",
+            VmaKind::Device(_) => "device",
+            VmaKind::Shared => "shared",
+            VmaKind::Stack{ .. } => "stack",
+            VmaKind::File{ .. } => "file",
+        }
+    }
+}
+
+/// Access that caused a page fault.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FaultAccess{
+    Read,
+    Write,
+    Execute,
+}
+
+/// Why an address space operation failed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VmError{
+    // Address or size is not page aligned, or outside the user half.
+    InvalidRange,
+    // Unknown permission bits.
+    InvalidProt,
+    // The range overlaps a region, or there is no room left.
+    NoSpace,
+    // No region at the address.
+    NoRegion,
+    // The region does not allow the access.
+    AccessDenied,
+    // The kernel touched user memory outside a user access.
+    KernelAccess,
+    // The page table could not be changed.
+    Map(MapError),
+}
+
+impl From<MapError> for VmError{
+    fn from(err: MapError) -> Self{
+        VmError::Map(err)
+    }
+}
+
+/// A user address space: its page table, sharing the kernel half, and the
+/// regions of the user half ordered by start address.
+pub struct AddressSpace{
+    page_table: PageTable,
+    vmas: BTreeMap<usize, Vma>,
+}
+
+impl AddressSpace{
+    /// Create an empty address space.
+    pub fn new() -> Option<Self>{
+        let mut page_table: PageTable = PageTable::new()?;
+        let mut layout = KERNEL_LAYOUT.lock();
+        page_table.share_kernel_half(layout.as_mut()?.page_table());
+        Some(Self{ page_table: page_table, vmas: BTreeMap::new() })
+    }
+
+    /// Page table of this address space.
+    pub fn page_table(&mut self) -> &mut PageTable{
+        &mut self.page_table
+    }
+
+    /// Load this address space.
+    pub fn activate(&self){
+        self.page_table.enable();
+    }
+
+    /// Region holding addr.
+    pub fn find_vma(&self, addr: usize) -> Option<&Vma>{
+        let (_, vma) = self.vmas.range(..=addr).next_back()?;
+        if vma.contains(addr) { Some(vma) } else { None }
+    }
+
+    /// Iterate regions by address.
+    pub fn vmas(&self) -> impl Iterator<Item = &Vma>{
+        self.vmas.values()
+    }
+
+    /// Check whether [start, end) is free.
+    fn is_free(&self, start: usize, end: usize) -> bool{
+        if start < USER_SPACE_START || end > USER_SPACE_END || start >= end{
+            return false;
+        }
+        match self.vmas.range(..end).next_back(){
+            Some((_, vma)) => vma.end <= start,
+            _ => true,
+        }
+    }
+
+    /// Find room for size bytes, first fit from MMAP_BASE.
+    fn find_free(&self, size: usize) -> Option<usize>{
+        // A region below MMAP_BASE may reach past it.
+        let mut start: usize = match self.vmas.range(..MMAP_BASE).next_back(){
+            Some((_, vma)) => vma.end.max(MMAP_BASE),
+            _ => MMAP_BASE,
+        };
+        for (_, vma) in self.vmas.range(start..){
+            if vma.start >= start.checked_add(size)?{
+                break;
+            }
+            start = start.max(vma.end);
+        }
+        if start.checked_add(size)? <= USER_SPACE_END { Some(start) } else { None }
+    }
+
+    /// Map a new region of size bytes, at addr or wherever there is room.
+    /// Anonymous and stack pages get a zeroed frame on first touch, other
+    /// pages are populated right away. Return the first usable address; a
+    /// guarded stack region gets its guard page below it.
+    pub fn mmap(&mut self, addr: Option<usize>, size: usize, prot: u32, kind: VmaKind) -> Result<usize, VmError>{
+        if size == 0 || size & (PAGE_SIZE - 1) != 0 || addr.unwrap_or(0) & (PAGE_SIZE - 1) != 0{
+            return Err(VmError::InvalidRange);
+        }
+        if prot & !PROT_MASK != 0{
+            return Err(VmError::InvalidProt);
+        }
+        if let VmaKind::Device(paddr) = kind{
+            if !paddr.is_page_aligned(){
+                return Err(VmError::InvalidRange);
+            }
+        }
+        let size: usize = match kind{
+            VmaKind::Stack{ guard: true } => size.checked_add(PAGE_SIZE).ok_or(VmError::InvalidRange)?,
+            _ => size,
+        };
+
+        let start: usize = match addr{
+            Some(start) => {
+                let end: usize = start.checked_add(size).ok_or(VmError::InvalidRange)?;
+                if !self.is_free(start, end){
+                    return Err(VmError::NoSpace);
+                }
+                start
+            }
+            _ => self.find_free(size).ok_or(VmError::NoSpace)?,
+        };
+        let vma: Vma = Vma{ start: start, end: start + size, prot: prot, kind: kind };
+        self.vmas.insert(start, vma);
+
+        // Pages are filled now, undo the region if memory runs out.
+        if prot != PROT_NONE && !vma.is_lazy(){
+            let mut page: usize = start;
+            while page < vma.end{
+                if let Err(err) = self.populate(&vma, page){
+                    let _ = self.munmap(start, size);
+                    return Err(err);
+                }
+                page += PAGE_SIZE;
+            }
+        }
+
+        match kind{
+            VmaKind::Stack{ guard: true } => Ok(start + PAGE_SIZE),
+            _ => Ok(start),
+        }
+    }
+
+    /// Back one page of a region, as its kind says.
+    fn populate(&mut self, vma: &Vma, page: usize) -> Result<(), VmError>{
+        let flags: PTEFlags = vma.pte_flags();
+        let paddr: PhysAddr = match vma.kind{
+            VmaKind::Device(paddr) => {
+                let paddr: PhysAddr = paddr + (page - vma.start);
+                self.page_table.map(VirtAddr::from(page), paddr, flags)?;
+                return Ok(());
+            }
+            VmaKind::Stack{ guard: true } if page == vma.start => {
+                // Guard page.
+                return Ok(());
+            }
+            _ => phys_pages_alloc_typed(0, PageType::Anon).ok_or(VmError::Map(MapError::OutOfMemory))?,
+        };
+
+        set_frame(paddr, 0);
+        if let VmaKind::File{ data, offset } = vma.kind{
+            let file_offset: usize = offset + (page - vma.start);
+            if file_offset < data.len(){
+                let len: usize = (data.len() - file_offset).min(PAGE_SIZE);
+                unsafe{
+                    copy_nonoverlapping(data.as_ptr().add(file_offset), phys_to_virt(paddr).to_mut_ptr() as *mut u8, len);
+                }
+            }
+        }
+
+        if let Err(err) = self.page_table.map(VirtAddr::from(page), paddr, flags){
+            phys_page_put(paddr);
+            return Err(VmError::from(err));
+        }
+        Ok(())
+    }
+
+    /// Split the region holding addr so that a region starts at addr.
+    fn split_at(&mut self, addr: usize){
+        let vma: Vma = match self.find_vma(addr){
+            Some(vma) if vma.start != addr => *vma,
+            _ => { return ; }
+        };
+        let (low, high) = vma.split(addr);
+        self.vmas.insert(low.start, low);
+        self.vmas.insert(high.start, high);
+    }
+
+    /// Starts of the regions inside [start, end), after splitting the ones
+    /// crossing its bounds.
+    fn isolate(&mut self, start: usize, end: usize) -> Vec<usize>{
+        self.split_at(start);
+        self.split_at(end);
+        self.vmas.range(start..end).map(|(start, _)| *start).collect()
+    }
+
+    /// Runs of pages of a region that hold a frame, present or not.
+    fn mapped_runs(&self, vma: &Vma) -> Vec<Mapping>{
+        let mut runs: Vec<Mapping> = Vec::new();
+        self.page_table.walk_range_hidden(VirtAddr::from(vma.start), VirtAddr::from(vma.end),
+            &mut |mapping: &Mapping| runs.push(*mapping));
+        runs
+    }
+
+    /// Set the flags of every page of a run.
+    fn set_run_flags(&mut self, run: &Mapping, flags: PTEFlags){
+        let mut offset: usize = 0;
+        while offset < run.size{
+            if let Some((pte, _)) = self.page_table.get_pte(run.virt_start + offset){
+                pte.set_flags(flags);
+            }
+            offset += PAGE_SIZE;
+        }
+        self.page_table.flush_range(run.virt_start, run.virt_start + run.size);
+    }
+
+    /// Unmap pages of a region, and drop its frames.
+    fn unmap_vma(&mut self, vma: &Vma){
+        let runs: Vec<Mapping> = self.mapped_runs(vma);
+        // Pages made not present are not seen by unmap_region.
+        for run in runs.iter().filter(|run| !run.flags.is_contain(PRESENT)){
+            let mut offset: usize = 0;
+            while offset < run.size{
+                if let Some((pte, _)) = self.page_table.get_pte(run.virt_start + offset){
+                    pte.set_unused();
+                }
+                offset += PAGE_SIZE;
+            }
+        }
+        let _ = self.page_table.unmap_region(VirtAddr::from(vma.start), vma.size());
+        if vma.owns_frames(){
+            for run in runs.iter(){
+                let mut offset: usize = 0;
+                while offset < run.size{
+                    phys_page_put(run.phys_start + offset);
+                    offset += PAGE_SIZE;
+                }
+            }
+        }
+    }
+
+    /// Remove regions in [addr, addr + size). Regions partly inside shrink
+    /// or split.
+    pub fn munmap(&mut self, addr: usize, size: usize) -> Result<(), VmError>{
+        if size == 0 || addr & (PAGE_SIZE - 1) != 0 || size & (PAGE_SIZE - 1) != 0
+            || addr.checked_add(size).is_none(){
+            return Err(VmError::InvalidRange);
+        }
+        for start in self.isolate(addr, addr + size){
+            if let Some(vma) = self.vmas.remove(&start){
+                self.unmap_vma(&vma);
+            }
+        }
+        Ok(())
+    }
+
+    /// Change permissions of regions in [addr, addr + size). Every page of
+    /// the range must belong to a region. With PROT_NONE the pages become
+    /// guards, and keep their content.
+    pub fn mprotect(&mut self, addr: usize, size: usize, prot: u32) -> Result<(), VmError>{
+        if size == 0 || addr & (PAGE_SIZE - 1) != 0 || size & (PAGE_SIZE - 1) != 0
+            || addr.checked_add(size).is_none(){
+            return Err(VmError::InvalidRange);
+        }
+        if prot & !PROT_MASK != 0{
+            return Err(VmError::InvalidProt);
+        }
+        let end: usize = addr + size;
+        let mut covered: usize = addr;
+        for (_, vma) in self.vmas.range(..end){
+            if vma.end <= covered{
+                continue;
+            }
+            if vma.start > covered{
+                break;
+            }
+            covered = vma.end;
+        }
+        if covered < end{
+            return Err(VmError::NoRegion);
+        }
+
+        for start in self.isolate(addr, end){
+            let vma: &mut Vma = match self.vmas.get_mut(&start){
+                Some(vma) => vma,
+                _ => { continue; }
+            };
+            vma.prot = prot;
+            let vma: Vma = *vma;
+
+            // Only pages mapped so far change. Without access they are made
+            // not present, and keep their frame for when access comes back.
+            let runs: Vec<Mapping> = self.mapped_runs(&vma);
+            for run in runs.iter(){
+                let flags: PTEFlags = if prot == PROT_NONE{
+                    PTEFlags::new(run.flags.as_u64() & !PRESENT)
+                }
+                else{
+                    vma.pte_flags()
+                };
+                self.set_run_flags(run, flags);
+            }
+            // Frames still shared with a clone stay read-only.
+            if vma.is_private() && vma.prot & PROT_WRITE != 0{
+                for run in runs.iter(){
+                    self.cow_protect(run);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Make pages of a mapped run read-only where their frame is shared.
+    fn cow_protect(&mut self, run: &Mapping){
+        let mut offset: usize = 0;
+        while offset < run.size{
+            if phys_page_refcount(run.phys_start + offset) > 1{
+                if let Some((pte, _)) = self.page_table.get_pte(run.virt_start + offset){
+                    let flags: u64 = pte.flags().as_u64() & !WRITABLE;
+                    pte.set_flags(PTEFlags::new(flags));
+                }
+            }
+            offset += PAGE_SIZE;
+        }
+        self.page_table.flush_range(run.virt_start, run.virt_start + run.size);
+    }
+
+    /// Copy this address space. Frames of private regions are shared by
+    /// both copies, read-only, until one of them writes; device and shared
+    /// regions map the same frames in both.
+    pub fn clone_cow(&mut self) -> Result<AddressSpace, VmError>{
+        let mut child: AddressSpace = AddressSpace::new().ok_or(VmError::Map(MapError::OutOfMemory))?;
+        let vmas: Vec<Vma> = self.vmas.values().copied().collect();
+        for vma in vmas.iter(){
+            child.vmas.insert(vma.start, *vma);
+
+            let runs: Vec<Mapping> = self.mapped_runs(vma);
+            for run in runs.iter(){
+                let mut flags: PTEFlags = run.flags;
+                if vma.is_private(){
+                    flags = PTEFlags::new(flags.as_u64() & !WRITABLE);
+                }
+                let mut offset: usize = 0;
+                while offset < run.size{
+                    let paddr: PhysAddr = run.phys_start + offset;
+                    child.page_table.map(run.virt_start + offset, paddr, flags)?;
+                    if vma.owns_frames(){
+                        phys_page_get(paddr);
+                    }
+                    offset += PAGE_SIZE;
+                }
+                if vma.is_private(){
+                    self.cow_protect(run);
+                }
+            }
+        }
+        Ok(child)
+    }
+
+    /// Resolve a write to a copy-on-write page. The frame is copied, unless
+    /// this address space is its last owner and can simply write to it.
+    fn cow_fault(&mut self, vma: &Vma, page: usize) -> Result<(), VmError>{
+        let vaddr: VirtAddr = VirtAddr::from(page);
+        let (paddr, _) = self.page_table.translate(vaddr).ok_or(VmError::NoRegion)?;
+        if phys_page_refcount(paddr) > 1{
+            let copy: PhysAddr = phys_pages_alloc_typed(0, PageType::Anon)
+                .ok_or(VmError::Map(MapError::OutOfMemory))?;
+            unsafe{
+                copy_nonoverlapping(phys_to_virt(paddr).to_mut_ptr() as *const u8,
+                    phys_to_virt(copy).to_mut_ptr() as *mut u8, PAGE_SIZE);
+            }
+            let (pte, _) = self.page_table.get_pte(vaddr).ok_or(VmError::NoRegion)?;
+            *pte = PTE::new_page_entry(copy, vma.pte_flags());
+            phys_page_put(paddr);
+        }
+        else{
+            let (pte, _) = self.page_table.get_pte(vaddr).ok_or(VmError::NoRegion)?;
+            pte.set_flags(vma.pte_flags());
+        }
+        self.page_table.flush_page(vaddr, PageSize::Size4K);
+        Ok(())
+    }
+
+    /// Resolve a fault at addr against the regions. Return an error if the
+    /// access is not allowed.
+    pub fn handle_fault(&mut self, addr: usize, access: FaultAccess) -> Result<(), VmError>{
+        let vma: Vma = *self.find_vma(addr).ok_or(VmError::NoRegion)?;
+        if !vma.allows(access){
+            return Err(VmError::AccessDenied);
+        }
+
+        let page: usize = addr & !(PAGE_SIZE - 1);
+        if let VmaKind::Stack{ guard: true } = vma.kind{
+            if page == vma.start{
+                return Err(VmError::AccessDenied);
+            }
+        }
+        // Mapped already. If the page allows the access, the fault came from
+        // a stale TLB entry.
+        if let Some(flags) = self.page_table.query(VirtAddr::from(page)){
+            let allowed: bool = match access{
+                FaultAccess::Read => true,
+                FaultAccess::Write => flags.is_contain(WRITABLE),
+                FaultAccess::Execute => !flags.is_contain(NO_EXECUTE),
+            };
+            if !allowed{
+                // A private writable page mapped read-only is copy-on-write.
+                if access == FaultAccess::Write && vma.is_private(){
+                    return self.cow_fault(&vma, page);
+                }
+                return Err(VmError::AccessDenied);
+            }
+            self.page_table.flush_page(VirtAddr::from(page), PageSize::Size4K);
+            return Ok(());
+        }
+        self.populate(&vma, page)
+    }
+
+    /// Print all regions.
+    pub fn print(&self){
+        for vma in self.vmas.values(){
+            println!("[+] {:016x}-{:016x} {}{}{} {}", vma.start, vma.end,
+                if vma.prot & PROT_READ != 0 { 'r' } else { '-' },
+                if vma.prot & PROT_WRITE != 0 { 'w' } else { '-' },
+                if vma.prot & PROT_EXEC != 0 { 'x' } else { '-' },
+                vma.kind_name());
+        }
+    }
+}
+
+/// Regions give their frames back. The page table then frees its tables.
+impl Drop for AddressSpace{
+    fn drop(&mut self){
+        let vmas: Vec<Vma> = self.vmas.values().copied().collect();
+        for vma in vmas.iter(){
+            self.unmap_vma(vma);
+        }
+        self.vmas.clear();
+    }
+}
+
+// Address space of the running program, if any. The kernel page table is
+// loaded otherwise.
+static CURRENT_SPACE: Mutex<Option<AddressSpace>> = Mutex::new(None);
+
+/// Make space the current address space and load it, or go back to the
+/// kernel page table. Return the previous one.
+pub fn address_space_switch(space: Option<AddressSpace>) -> Option<AddressSpace>{
+    let mut current = CURRENT_SPACE.lock();
+    match space.as_ref(){
+        Some(space) => space.activate(),
+        _ => {
+            if let Some(layout) = KERNEL_LAYOUT.lock().as_ref(){
+                layout.enable();
+            }
+        }
+    }
+    core::mem::replace(&mut *current, space)
+}
+
+/// Resolve a page fault at addr in the current address space. Fails when
+/// there is none, or it is busy, as the faulting code may hold it.
+pub fn address_space_fault(addr: usize, access: FaultAccess) -> Result<(), VmError>{
+    let mut current = CURRENT_SPACE.try_lock().ok_or(VmError::NoRegion)?;
+    current.as_mut().ok_or(VmError::NoRegion)?.handle_fault(addr, access)
+}
@@ -0,0 +1,142 @@
+#![allow(dead_code)]
+
+use super::frame::{pfn_of, pfn_to_page, pfn_to_phys, PageList, PageType, NO_ORDER};
+use super::page_table_entry::PhysAddr;
+use super::phys_page::PAGE_SIZE;
+
+/// Largest block order, 2^10 pages (4MB).
+pub const MAX_ORDER: usize = 10;
+
+/// Size in bytes of a block of the given order.
+#[inline]
+pub const fn order_size(order: usize) -> usize{
+    PAGE_SIZE << order
+}
+
+/// Smallest order whose block holds `size` bytes.
+pub fn size_to_order(size: usize) -> usize{
+    let mut order: usize = 0;
+    while order_size(order) < size{
+        order += 1;
+    }
+    order
+}
+
+/// Buddy allocator over physical pages.
+///
+/// Every free block of order k is 2^k pages, aligned to its own size, and
+/// linked into free_lists[k] through the frame table. The first frame of a
+/// free block is marked Free with order k, so a buddy is checked without
+/// searching the lists.
+pub struct BuddyAllocator{
+    free_lists: [PageList; MAX_ORDER + 1],
+    free_pages: usize,
+}
+
+impl BuddyAllocator{
+    /// Create an empty buddy allocator.
+    pub const fn new() -> Self{
+        Self{
+            free_lists: [const { PageList::new() }; MAX_ORDER + 1],
+            free_pages: 0,
+        }
+    }
+
+    /// Number of free pages.
+    pub fn free_pages(&self) -> usize{
+        self.free_pages
+    }
+
+    /// Number of free blocks of an order.
+    pub fn free_blocks(&self, order: usize) -> usize{
+        self.free_lists[order].len()
+    }
+
+    /// Add a free block of an order starting at frame pfn.
+    fn push_block(&mut self, pfn: usize, order: usize){
+        if let Some(page) = pfn_to_page(pfn){
+            page.set_order(order);
+        }
+        self.free_lists[order].push(pfn);
+    }
+
+    /// Take a free block out of its list.
+    fn remove_block(&mut self, pfn: usize, order: usize){
+        self.free_lists[order].remove(pfn);
+        if let Some(page) = pfn_to_page(pfn){
+            page.set_order(NO_ORDER);
+        }
+    }
+
+    /// Check whether frame pfn starts a free block of an order.
+    fn is_free_block(&self, pfn: usize, order: usize) -> bool{
+        match pfn_to_page(pfn){
+            Some(page) => page.page_type() == PageType::Free && page.order() == order,
+            _ => false,
+        }
+    }
+
+    /// Add physical memory [start, end) as free blocks. Both ends must be page aligned.
+    pub fn add_area(&mut self, start: usize, end: usize){
+        let mut curr: usize = start;
+        while curr < end{
+            // Largest block that is aligned at curr and fits in the area.
+            let mut order: usize = MAX_ORDER;
+            while curr & (order_size(order) - 1) != 0 || curr + order_size(order) > end{
+                order -= 1;
+            }
+            self.push_block(pfn_of(PhysAddr::from(curr)), order);
+            self.free_pages += 1 << order;
+            curr += order_size(order);
+        }
+    }
+
+    /// Allocate 2^order contiguous pages.
+    pub fn alloc(&mut self, order: usize) -> Option<PhysAddr>{
+        if order > MAX_ORDER{
+            return None;
+        }
+
+        // Find the smallest non-empty list.
+        let mut curr_order: usize = order;
+        while curr_order <= MAX_ORDER && self.free_lists[curr_order].is_empty(){
+            curr_order += 1;
+        }
+        if curr_order > MAX_ORDER{
+            return None;
+        }
+
+        // Split blocks down to the requested order.
+        let block: usize = self.free_lists[curr_order].pop()?;
+        if let Some(page) = pfn_to_page(block){
+            page.set_order(NO_ORDER);
+        }
+        while curr_order > order{
+            curr_order -= 1;
+            self.push_block(block + (1 << curr_order), curr_order);
+        }
+
+        self.free_pages -= 1 << order;
+        Some(pfn_to_phys(block))
+    }
+
+    /// Free 2^order contiguous pages starting at paddr, merging with free
+    /// buddies. The frames must be marked Free already.
+    pub fn free(&mut self, paddr: PhysAddr, order: usize){
+        let mut block: usize = pfn_of(paddr);
+        let mut curr_order: usize = order;
+        self.free_pages += 1 << order;
+
+        while curr_order < MAX_ORDER{
+            let buddy: usize = block ^ (1 << curr_order);
+            if !self.is_free_block(buddy, curr_order){
+                break;
+            }
+            self.remove_block(buddy, curr_order);
+            block = if block < buddy { block } else { buddy };
+            curr_order += 1;
+        }
+
+        self.push_block(block, curr_order);
+    }
+}
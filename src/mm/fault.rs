@@ -0,0 +1,119 @@
+#![allow(dead_code)]
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+use crate::println;
+
+use super::address_space::{address_space_fault, FaultAccess, VmError, USER_SPACE_END};
+use super::kstack::kstack_guard_hit;
+
+/// Page fault error code bits.
+pub const PF_PRESENT: u64 =  1;
+pub const PF_WRITE: u64 =    1 << 1;
+pub const PF_USER: u64 =     1 << 2;
+pub const PF_RESERVED: u64 = 1 << 3;
+pub const PF_INSTR: u64 =    1 << 4;
+pub const PF_PKEY: u64 =     1 << 5;
+
+/// A page fault, as reported by the CPU.
+#[derive(Clone, Copy, Debug)]
+pub struct PageFault{
+    // Faulting address, from cr2.
+    pub addr: usize,
+    // Faulting instruction.
+    pub rip: usize,
+    pub error_code: u64,
+}
+
+impl PageFault{
+    /// Access that faulted.
+    pub fn access(&self) -> FaultAccess{
+        if self.error_code & PF_INSTR != 0{
+            FaultAccess::Execute
+        }
+        else if self.error_code & PF_WRITE != 0{
+            FaultAccess::Write
+        }
+        else{
+            FaultAccess::Read
+        }
+    }
+
+    /// Check whether user code faulted.
+    pub fn is_user(&self) -> bool{
+        self.error_code & PF_USER != 0
+    }
+
+    /// Check whether the page was present, so the access broke its
+    /// permissions.
+    pub fn is_present(&self) -> bool{
+        self.error_code & PF_PRESENT != 0
+    }
+
+    /// Print a report of the fault.
+    pub fn print(&self){
+        println!("[Err] Page fault at rip: {:x}, addr: {:x}, error: {:x}", self.rip, self.addr, self.error_code);
+        println!("[Err] {} {:?} of {} page{}{}",
+            if self.is_user() { "User" } else { "Kernel" },
+            self.access(),
+            if self.is_present() { "present" } else { "missing" },
+            if self.error_code & PF_RESERVED != 0 { ", reserved bit set" } else { "" },
+            if self.error_code & PF_PKEY != 0 { ", protection key" } else { "" });
+        if kstack_guard_hit(self.addr){
+            println!("[Err] Kernel stack overflow.");
+        }
+    }
+}
+
+/// Called for user faults that cannot be resolved. It does not return to the
+/// faulting code.
+pub type UserFaultHandler = fn(&PageFault, VmError) -> !;
+
+// Handler delivering faults to the process, none until there are processes.
+static USER_FAULT_HANDLER: Mutex<Option<UserFaultHandler>> = Mutex::new(None);
+
+/// Set the handler for user faults that cannot be resolved.
+pub fn set_user_fault_handler(handler: UserFaultHandler){
+    *USER_FAULT_HANDLER.lock() = Some(handler);
+}
+
+/// Get the handler for user faults that cannot be resolved.
+pub fn user_fault_handler() -> Option<UserFaultHandler>{
+    *USER_FAULT_HANDLER.lock()
+}
+
+// Set while the kernel accesses user memory on purpose.
+// TODO: per-CPU once other CPUs are brought up.
+static USER_ACCESS: AtomicBool = AtomicBool::new(false);
+
+/// Run f as a user access: kernel faults on user addresses are resolved like
+/// user faults meanwhile.
+pub fn with_user_access<R>(f: impl FnOnce() -> R) -> R{
+    let prev: bool = USER_ACCESS.swap(true, Ordering::AcqRel);
+    let result: R = f();
+    USER_ACCESS.store(prev, Ordering::Release);
+    result
+}
+
+/// Resolve a page fault in the current address space, backing the page if
+/// its region allows the access. Only user addresses are resolved, and only
+/// for user code or kernel code inside with_user_access. The kernel never
+/// runs user pages.
+pub fn page_fault_resolve(fault: &PageFault) -> Result<(), VmError>{
+    if fault.error_code & (PF_RESERVED | PF_PKEY) != 0{
+        return Err(VmError::AccessDenied);
+    }
+    if fault.addr >= USER_SPACE_END{
+        return Err(VmError::NoRegion);
+    }
+    if !fault.is_user(){
+        if fault.access() == FaultAccess::Execute{
+            return Err(VmError::AccessDenied);
+        }
+        if !USER_ACCESS.load(Ordering::Acquire){
+            return Err(VmError::KernelAccess);
+        }
+    }
+    address_space_fault(fault.addr, fault.access())
+}
@@ -0,0 +1,315 @@
+#![allow(dead_code)]
+
+use core::mem::size_of;
+use core::sync::atomic::{AtomicU32, AtomicU8, AtomicUsize, Ordering};
+
+use super::page_table_entry::PhysAddr;
+use super::phys_page::{phys_to_virt, PhysArea, PhysAreas, BOOT_MAPPED_LIMIT, PAGE_SIZE, page_align_up};
+
+/// What a physical frame is used for.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum PageType{
+    Free,
+    Reserved,
+    Kernel,
+    PageTable,
+    Anon,
+    Dma,
+    Heap,
+    Slab,
+}
+
+/// Number of page types.
+pub const NUM_PAGE_TYPES: usize = 8;
+
+impl PageType{
+    /// Page type stored as u8.
+    fn from_u8(value: u8) -> Self{
+        match value{
+            0 => PageType::Free,
+            1 => PageType::Reserved,
+            2 => PageType::Kernel,
+            3 => PageType::PageTable,
+            4 => PageType::Anon,
+            5 => PageType::Dma,
+            6 => PageType::Heap,
+            _ => PageType::Slab,
+        }
+    }
+}
+
+// Number of frames of each type.
+static PAGE_TYPE_COUNT: [AtomicUsize; NUM_PAGE_TYPES] = [const { AtomicUsize::new(0) }; NUM_PAGE_TYPES];
+
+/// Number of frames of a type.
+pub fn frames_of_type(page_type: PageType) -> usize{
+    PAGE_TYPE_COUNT[page_type as usize].load(Ordering::Relaxed)
+}
+
+/// No frame, end of a frame list.
+pub const NO_PFN: u32 = u32::MAX;
+/// Order of a frame that does not start a block.
+pub const NO_ORDER: usize = 0xff;
+
+/// Metadata of one physical frame. Shared by every lookup of the frame, so
+/// all fields are atomics.
+#[repr(C)]
+pub struct Page{
+    refcount: AtomicU32,
+    page_type: AtomicU8,
+    // Block order, only meaningful on the first frame of a block.
+    order: AtomicU8,
+    // Frame list links.
+    next: AtomicU32,
+    prev: AtomicU32,
+}
+
+impl Page{
+    /// Current reference count.
+    #[inline]
+    pub fn refcount(&self) -> u32{
+        self.refcount.load(Ordering::Acquire)
+    }
+
+    /// Set the reference count.
+    #[inline]
+    pub fn set_refcount(&self, count: u32){
+        self.refcount.store(count, Ordering::Release);
+    }
+
+    /// Take a reference, return the new count.
+    #[inline]
+    pub fn get(&self) -> u32{
+        self.refcount.fetch_add(1, Ordering::AcqRel) + 1
+    }
+
+    /// Drop a reference, return the new count.
+    #[inline]
+    pub fn put(&self) -> u32{
+        self.refcount.fetch_sub(1, Ordering::AcqRel) - 1
+    }
+
+    /// What this frame is used for.
+    #[inline]
+    pub fn page_type(&self) -> PageType{
+        PageType::from_u8(self.page_type.load(Ordering::Acquire))
+    }
+
+    /// Set what this frame is used for.
+    #[inline]
+    pub fn set_type(&self, page_type: PageType){
+        let old: u8 = self.page_type.swap(page_type as u8, Ordering::AcqRel);
+        PAGE_TYPE_COUNT[old as usize].fetch_sub(1, Ordering::Relaxed);
+        PAGE_TYPE_COUNT[page_type as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Block order.
+    #[inline]
+    pub fn order(&self) -> usize{
+        self.order.load(Ordering::Acquire) as usize
+    }
+
+    /// Set block order.
+    #[inline]
+    pub fn set_order(&self, order: usize){
+        self.order.store(order as u8, Ordering::Release);
+    }
+
+    /// Next frame in a list.
+    #[inline]
+    pub fn next(&self) -> u32{
+        self.next.load(Ordering::Acquire)
+    }
+
+    /// Set next frame in a list.
+    #[inline]
+    pub fn set_next(&self, pfn: u32){
+        self.next.store(pfn, Ordering::Release);
+    }
+
+    /// Previous frame in a list.
+    #[inline]
+    pub fn prev(&self) -> u32{
+        self.prev.load(Ordering::Acquire)
+    }
+
+    /// Set previous frame in a list.
+    #[inline]
+    pub fn set_prev(&self, pfn: u32){
+        self.prev.store(pfn, Ordering::Release);
+    }
+}
+
+// Frame table base (virtual) and number of entries. Written once at boot.
+static FRAME_TABLE_BASE: AtomicUsize = AtomicUsize::new(0);
+static FRAME_TABLE_LEN: AtomicUsize = AtomicUsize::new(0);
+
+/// Frame number of a physical address.
+#[inline]
+pub fn pfn_of(paddr: PhysAddr) -> usize{
+    paddr.to_usize() / PAGE_SIZE
+}
+
+/// Physical address of a frame number.
+#[inline]
+pub fn pfn_to_phys(pfn: usize) -> PhysAddr{
+    PhysAddr::from(pfn * PAGE_SIZE)
+}
+
+/// Number of frames covered by the frame table.
+pub fn frame_table_len() -> usize{
+    FRAME_TABLE_LEN.load(Ordering::Acquire)
+}
+
+/// Get metadata of a frame by number.
+pub fn pfn_to_page(pfn: usize) -> Option<&'static Page>{
+    if pfn >= frame_table_len(){
+        return None;
+    }
+    let base: *const Page = FRAME_TABLE_BASE.load(Ordering::Acquire) as *const Page;
+    unsafe{ Some(&*base.add(pfn)) }
+}
+
+/// Get metadata of the frame holding a physical address.
+#[inline]
+pub fn page_of(paddr: PhysAddr) -> Option<&'static Page>{
+    pfn_to_page(pfn_of(paddr))
+}
+
+/// Set type of 2^order frames starting at paddr.
+pub fn set_frames_type(paddr: PhysAddr, order: usize, page_type: PageType){
+    let pfn: usize = pfn_of(paddr);
+    for i in 0..(1usize << order){
+        if let Some(page) = pfn_to_page(pfn + i){
+            page.set_type(page_type);
+        }
+    }
+}
+
+/// Build the frame table covering every usable frame, and take its memory
+/// out of the usable areas. Must run before the page allocator is seeded.
+pub fn frame_table_init(areas: &mut PhysAreas) -> bool{
+    // Highest usable frame decides the table size.
+    let mut max_pfn: usize = 0;
+    for i in 0..areas.len(){
+        let area: PhysArea = areas.get(i).unwrap();
+        if area.end / PAGE_SIZE > max_pfn{
+            max_pfn = area.end / PAGE_SIZE;
+        }
+    }
+    let table_size: usize = page_align_up(max_pfn * size_of::<Page>());
+
+    // Place the table in the first area large enough, inside the boot
+    // direct map.
+    let mut table_phys: Option<usize> = None;
+    for i in 0..areas.len(){
+        let area: PhysArea = areas.get(i).unwrap();
+        if area.size() >= table_size && area.start + table_size <= BOOT_MAPPED_LIMIT{
+            table_phys = Some(area.start);
+            break;
+        }
+    }
+    let table_phys: usize = match table_phys{
+        Some(start) => start,
+        _ => { return false; }
+    };
+    areas.reserve(table_phys, table_phys + table_size);
+
+    // Every frame is reserved, unless it is in a usable area.
+    let base: *mut Page = phys_to_virt(PhysAddr::from(table_phys)).to_mut_ptr() as *mut Page;
+    for pfn in 0..max_pfn{
+        unsafe{
+            base.add(pfn).write(Page{
+                refcount: AtomicU32::new(0),
+                page_type: AtomicU8::new(PageType::Reserved as u8),
+                order: AtomicU8::new(NO_ORDER as u8),
+                next: AtomicU32::new(NO_PFN),
+                prev: AtomicU32::new(NO_PFN),
+            });
+        }
+    }
+    PAGE_TYPE_COUNT[PageType::Reserved as usize].store(max_pfn, Ordering::Relaxed);
+    FRAME_TABLE_BASE.store(base as usize, Ordering::Release);
+    FRAME_TABLE_LEN.store(max_pfn, Ordering::Release);
+
+    for i in 0..areas.len(){
+        let area: PhysArea = areas.get(i).unwrap();
+        for pfn in area.start / PAGE_SIZE..area.end / PAGE_SIZE{
+            pfn_to_page(pfn).unwrap().set_type(PageType::Free);
+        }
+    }
+    for pfn in table_phys / PAGE_SIZE..(table_phys + table_size) / PAGE_SIZE{
+        if let Some(page) = pfn_to_page(pfn){
+            page.set_type(PageType::Kernel);
+            page.set_refcount(1);
+        }
+    }
+    true
+}
+
+/// Doubly linked list of frames, through the links in their metadata.
+pub struct PageList{
+    head: u32,
+    len: usize,
+}
+
+impl PageList{
+    /// Create an empty frame list.
+    pub const fn new() -> Self{
+        Self{ head: NO_PFN, len: 0 }
+    }
+
+    /// Number of frames in the list.
+    pub fn len(&self) -> usize{
+        self.len
+    }
+
+    /// Check empty.
+    pub fn is_empty(&self) -> bool{
+        self.head == NO_PFN
+    }
+
+    /// Push a frame to front.
+    pub fn push(&mut self, pfn: usize){
+        let page: &Page = match pfn_to_page(pfn){
+            Some(page) => page,
+            _ => { return ; }
+        };
+        page.set_prev(NO_PFN);
+        page.set_next(self.head);
+        if let Some(head) = pfn_to_page(self.head as usize){
+            head.set_prev(pfn as u32);
+        }
+        self.head = pfn as u32;
+        self.len += 1;
+    }
+
+    /// Remove a frame that is in this list.
+    pub fn remove(&mut self, pfn: usize){
+        let page: &Page = match pfn_to_page(pfn){
+            Some(page) => page,
+            _ => { return ; }
+        };
+        match pfn_to_page(page.prev() as usize){
+            Some(prev) => { prev.set_next(page.next()); }
+            _ => { self.head = page.next(); }
+        }
+        if let Some(next) = pfn_to_page(page.next() as usize){
+            next.set_prev(page.prev());
+        }
+        page.set_next(NO_PFN);
+        page.set_prev(NO_PFN);
+        self.len -= 1;
+    }
+
+    /// Pop a frame from front.
+    pub fn pop(&mut self) -> Option<usize>{
+        if self.is_empty(){
+            return None;
+        }
+        let pfn: usize = self.head as usize;
+        self.remove(pfn);
+        Some(pfn)
+    }
+}
@@ -0,0 +1,215 @@
+#![allow(dead_code)]
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::cmp::max;
+use core::mem::size_of;
+use core::ptr::null_mut;
+use spin::Mutex;
+
+use crate::utils::linked_list::LinkedList;
+use crate::println;
+
+use super::buddy::{order_size, size_to_order};
+use super::frame::PageType;
+use super::phys_page::{phys_pages_alloc_typed, phys_to_virt};
+
+/// Number of block sizes, from 2^0 to 2^(HEAP_ORDERS - 1) bytes.
+pub const HEAP_ORDERS: usize = 32;
+/// Initial kernel heap size as a page order, 2^6 pages (256KB).
+pub const HEAP_INIT_ORDER: usize = 6;
+/// Heap grows at least by 2^4 pages (64KB).
+pub const HEAP_GROW_ORDER: usize = 4;
+
+/// Buddy heap over bytes.
+///
+/// Free blocks of 2^k bytes are aligned to their size and linked into
+/// free_lists[k] through their first word.
+pub struct Heap{
+    free_lists: [LinkedList; HEAP_ORDERS],
+    // Bytes requested by callers.
+    user: usize,
+    // Bytes handed out, including rounding.
+    allocated: usize,
+    // Bytes owned by the heap.
+    total: usize,
+}
+
+impl Heap{
+    /// Create an empty heap.
+    pub const fn new() -> Self{
+        Self{
+            free_lists: [LinkedList::new(); HEAP_ORDERS],
+            user: 0,
+            allocated: 0,
+            total: 0,
+        }
+    }
+
+    /// Bytes requested by callers.
+    pub fn user_bytes(&self) -> usize{
+        self.user
+    }
+
+    /// Bytes handed out, including rounding.
+    pub fn allocated_bytes(&self) -> usize{
+        self.allocated
+    }
+
+    /// Bytes owned by the heap.
+    pub fn total_bytes(&self) -> usize{
+        self.total
+    }
+
+    /// Add memory [start, end) to the heap.
+    pub fn add_to_heap(&mut self, start: usize, end: usize){
+        let unit: usize = size_of::<usize>();
+        let mut curr: usize = (start + unit - 1) & !(unit - 1);
+        let end: usize = end & !(unit - 1);
+
+        while curr + unit <= end{
+            // Largest block that is aligned at curr and fits in the area.
+            let mut order: usize = curr.trailing_zeros() as usize;
+            if order >= HEAP_ORDERS{
+                order = HEAP_ORDERS - 1;
+            }
+            while curr + (1 << order) > end{
+                order -= 1;
+            }
+            self.free_lists[order].push(curr as *mut usize);
+            self.total += 1 << order;
+            curr += 1 << order;
+        }
+    }
+
+    /// Block order needed by a layout.
+    fn layout_order(layout: &Layout) -> usize{
+        let size: usize = max(layout.size().next_power_of_two(), max(layout.align(), size_of::<usize>()));
+        size.trailing_zeros() as usize
+    }
+
+    /// Allocate a block for the layout.
+    pub fn alloc(&mut self, layout: Layout) -> Option<*mut u8>{
+        let order: usize = Self::layout_order(&layout);
+
+        let mut curr_order: usize = order;
+        while curr_order < HEAP_ORDERS && self.free_lists[curr_order].is_empty(){
+            curr_order += 1;
+        }
+        if curr_order >= HEAP_ORDERS{
+            return None;
+        }
+
+        // Split blocks down to the requested order.
+        let block: *mut usize = self.free_lists[curr_order].pop()?;
+        while curr_order > order{
+            curr_order -= 1;
+            let buddy: usize = block as usize + (1 << curr_order);
+            self.free_lists[curr_order].push(buddy as *mut usize);
+        }
+
+        self.user += layout.size();
+        self.allocated += 1 << order;
+        Some(block as *mut u8)
+    }
+
+    /// Free a block allocated with the same layout, merging with free buddies.
+    pub fn dealloc(&mut self, ptr: *mut u8, layout: Layout){
+        let order: usize = Self::layout_order(&layout);
+        self.user -= layout.size();
+        self.allocated -= 1 << order;
+
+        let mut block: usize = ptr as usize;
+        let mut curr_order: usize = order;
+        while curr_order < HEAP_ORDERS - 1{
+            let buddy: usize = block ^ (1 << curr_order);
+            let mut found: bool = false;
+            for node in self.free_lists[curr_order].iter_mut(){
+                if node.value() as usize == buddy{
+                    node.pop();
+                    found = true;
+                    break;
+                }
+            }
+
+            if !found{
+                break;
+            }
+            block = if block < buddy { block } else { buddy };
+            curr_order += 1;
+        }
+
+        self.free_lists[curr_order].push(block as *mut usize);
+    }
+}
+
+/// Kernel heap protected by a spin lock. Grows with physical pages on demand.
+pub struct LockedHeap{
+    heap: Mutex<Heap>,
+}
+
+impl LockedHeap{
+    /// Create an empty locked heap.
+    pub const fn new() -> Self{
+        Self{ heap: Mutex::new(Heap::new()) }
+    }
+
+    /// Add 2^order physical pages to the heap.
+    pub fn grow(&self, order: usize) -> bool{
+        match phys_pages_alloc_typed(order, PageType::Heap){
+            Some(paddr) => {
+                let start: usize = phys_to_virt(paddr).to_usize();
+                self.heap.lock().add_to_heap(start, start + order_size(order));
+                true
+            }
+            _ => {
+                false
+            }
+        }
+    }
+
+    /// Get (user, allocated, total) bytes of the heap.
+    pub fn stats(&self) -> (usize, usize, usize){
+        let heap = self.heap.lock();
+        (heap.user_bytes(), heap.allocated_bytes(), heap.total_bytes())
+    }
+}
+
+unsafe impl GlobalAlloc for LockedHeap{
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8{
+        if let Some(ptr) = self.heap.lock().alloc(layout){
+            return ptr;
+        }
+
+        // Out of heap memory, ask the frame allocator for more.
+        let size: usize = max(layout.size(), layout.align());
+        let order: usize = max(size_to_order(size), HEAP_GROW_ORDER);
+        if !self.grow(order){
+            return null_mut();
+        }
+        match self.heap.lock().alloc(layout){
+            Some(ptr) => ptr,
+            _ => null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout){
+        self.heap.lock().dealloc(ptr, layout);
+    }
+}
+
+#[global_allocator]
+pub static KERNEL_HEAP: LockedHeap = LockedHeap::new();
+
+/// Initialize kernel heap.
+pub fn kernel_heap_init(){
+    if !KERNEL_HEAP.grow(HEAP_INIT_ORDER){
+        println!("[Err] Failed allocate kernel heap.");
+    }
+}
+
+/// This function is called when a heap allocation fails.
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> !{
+    println!("[Err] Kernel heap allocation failed: size {}, align {}.", layout.size(), layout.align());
+    loop{}
+}
@@ -0,0 +1,123 @@
+#![allow(dead_code)]
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::layout::KERNEL_LAYOUT;
+use super::page_table_entry::{PTEFlags, VirtAddr, GLOBAL, NO_EXECUTE, PRESENT, WRITABLE};
+use super::phys_page::{page_align_up, phys_page_alloc, phys_page_free, PAGE_SIZE};
+
+/// Kernel stacks live in their own area, below the kernel image.
+pub const KSTACK_BASE: usize = 0xffffff0000000000;
+/// Every stack owns a slot: one unmapped guard page, then the stack.
+pub const KSTACK_SLOT_SIZE: usize = 0x100000;
+/// Number of slots in the area (512GB).
+pub const KSTACK_MAX_SLOTS: usize = 0x80000;
+/// Largest stack that fits in a slot.
+pub const KSTACK_MAX_SIZE: usize = KSTACK_SLOT_SIZE - PAGE_SIZE;
+/// Default size of a kernel stack, kstack= on the command line changes it.
+pub const KSTACK_DEFAULT_SIZE: usize = 0x4000;
+/// Size of interrupt stacks used through the IST.
+pub const KSTACK_IST_SIZE: usize = 0x4000;
+
+// Next unused slot. Slots are not reused.
+static NEXT_SLOT: AtomicUsize = AtomicUsize::new(0);
+
+/// Kernel stack [bottom, top), with an unmapped guard page below bottom.
+#[derive(Clone, Copy)]
+pub struct KernelStack{
+    bottom: usize,
+    top: usize,
+}
+
+impl KernelStack{
+    /// Lowest mapped address.
+    pub fn bottom(&self) -> usize{
+        self.bottom
+    }
+
+    /// Initial stack pointer.
+    pub fn top(&self) -> usize{
+        self.top
+    }
+
+    /// Size in bytes.
+    pub fn size(&self) -> usize{
+        self.top - self.bottom
+    }
+}
+
+/// Allocate a kernel stack of at least size bytes.
+pub fn kstack_alloc(size: usize) -> Option<KernelStack>{
+    let size: usize = page_align_up(size);
+    if size == 0 || size > KSTACK_MAX_SIZE{
+        return None;
+    }
+    let slot: usize = NEXT_SLOT.fetch_add(1, Ordering::Relaxed);
+    if slot >= KSTACK_MAX_SLOTS{
+        return None;
+    }
+
+    let bottom: usize = KSTACK_BASE + slot * KSTACK_SLOT_SIZE + PAGE_SIZE;
+    let stack: KernelStack = KernelStack{ bottom: bottom, top: bottom + size };
+
+    let mut layout = KERNEL_LAYOUT.lock();
+    let page_table = layout.as_mut()?.page_table();
+    let flags: PTEFlags = PTEFlags::new(PRESENT | WRITABLE | NO_EXECUTE | GLOBAL);
+    let mut vaddr: usize = stack.bottom;
+    while vaddr < stack.top{
+        let mapped: bool = match phys_page_alloc(){
+            Some(paddr) => {
+                let result = page_table.map(VirtAddr::from(vaddr), paddr, flags);
+                if result.is_err(){
+                    phys_page_free(paddr);
+                }
+                result.is_ok()
+            }
+            _ => false,
+        };
+        if !mapped{
+            // Give back what is mapped so far.
+            drop(layout);
+            kstack_free(KernelStack{ bottom: stack.bottom, top: vaddr });
+            return None;
+        }
+        vaddr += PAGE_SIZE;
+    }
+    Some(stack)
+}
+
+/// Unmap a kernel stack and free its frames. Its slot is not reused.
+pub fn kstack_free(stack: KernelStack){
+    let mut layout = KERNEL_LAYOUT.lock();
+    let page_table = match layout.as_mut(){
+        Some(layout) => layout.page_table(),
+        _ => { return ; }
+    };
+    let mut vaddr: usize = stack.bottom;
+    while vaddr < stack.top{
+        if let Some(paddr) = page_table.unmap(VirtAddr::from(vaddr)){
+            phys_page_free(paddr);
+        }
+        vaddr += PAGE_SIZE;
+    }
+}
+
+/// Check whether an address is in the guard page of a kernel stack.
+pub fn kstack_guard_hit(addr: usize) -> bool{
+    let slots: usize = NEXT_SLOT.load(Ordering::Relaxed);
+    addr >= KSTACK_BASE && addr < KSTACK_BASE + slots * KSTACK_SLOT_SIZE
+        && (addr - KSTACK_BASE) % KSTACK_SLOT_SIZE < PAGE_SIZE
+}
+
+/// Switch to a stack and continue in entry. The current stack is abandoned.
+pub fn kstack_switch(stack: &KernelStack, entry: extern "C" fn() -> !) -> !{
+    unsafe{
+        asm!("mov rsp, {top}
+              xor rbp, rbp
+              call {entry}",
+              top = in(reg) stack.top,
+              entry = in(reg) entry,
+              options(noreturn));
+    }
+}
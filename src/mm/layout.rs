@@ -1,38 +1,256 @@
 #![allow(dead_code)]
-#[macro_use]
 
-use multiboot2::BootInformationHeader;
+use spin::Mutex;
 
-use super::page_table::PageTable;
+use super::tlb::tlb_init;
+use super::page_table::{lcr0, rcr0, MapError, PageTable, CR0_WP, KERN_MAPPING_OFFSET};
+use super::page_table_entry::{PhysAddr, PTEFlags, VirtAddr, GLOBAL, NO_CACHE, NO_EXECUTE, PRESENT, WRITABLE,
+    WRITE_THROUGH};
+use super::phys_page::{page_align_down, page_align_up, phys_area_add, phys_area_reserve, phys_mem_init,
+    phys_mem_init_high, phys_to_virt, LOW_MEM_LIMIT, PAGE_SIZE, PHYS_AREAS, PHYS_TO_VIRT_BASE};
+use crate::asms::msr::{rdmsr, wrmsr, EFER_NXE, MSR_EFER};
+use crate::boot::boot_info::{BootInfo, MemAreaType, ELF_SECTION_ALLOCATED, ELF_SECTION_EXECUTABLE,
+    ELF_SECTION_WRITABLE};
+use crate::boot::cmdline::{kernel_options, LogLevel};
 use crate::println;
 
-/// Find all memory area from the boot information.
-pub fn find_kernel_areas(multiboot_info: usize)
+/// Legacy VGA memory, accessed uncached.
+pub const VGA_MEM_START: usize = 0xa0000;
+pub const VGA_MEM_END: usize =   0xc0000;
+
+extern "C"{
+    /// Start of the kernel image, defined in the linker script.
+    static _kernel_start: u8;
+    /// End of the kernel image, defined in the linker script.
+    static _kernel_end: u8;
+    /// Section bounds, defined in the linker script.
+    static _text_start: u8;
+    static _text_end: u8;
+    static _rodata_start: u8;
+    static _rodata_end: u8;
+    static _data_start: u8;
+}
+
+/// Physical range [start, end) occupied by the kernel image.
+pub fn kernel_image_range() -> (usize, usize){
+    let (start, end) = kernel_image_virt_range();
+    (start - KERN_MAPPING_OFFSET, end - KERN_MAPPING_OFFSET)
+}
+
+/// Virtual range [start, end) of the kernel image in the higher half.
+pub fn kernel_image_virt_range() -> (usize, usize){
+    unsafe{
+        (&_kernel_start as *const u8 as usize, &_kernel_end as *const u8 as usize)
+    }
+}
+
+/// Virtual ranges [start, end) of the kernel image with their page flags:
+/// boot trampoline, text, rodata, then data and bss.
+pub fn kernel_image_sections() -> [(usize, usize, u64); 4]{
+    let (kernel_start, kernel_end) = kernel_image_virt_range();
+    unsafe{
+        let text_start: usize = &_text_start as *const u8 as usize;
+        let text_end: usize = &_text_end as *const u8 as usize;
+        let rodata_start: usize = &_rodata_start as *const u8 as usize;
+        let rodata_end: usize = &_rodata_end as *const u8 as usize;
+        let data_start: usize = &_data_start as *const u8 as usize;
+        [
+            (kernel_start, text_start, PRESENT | WRITABLE | NO_EXECUTE),
+            (text_start, text_end, PRESENT),
+            (rodata_start, rodata_end, PRESENT | NO_EXECUTE),
+            (data_start, kernel_end, PRESENT | WRITABLE | NO_EXECUTE),
+        ]
+    }
+}
+
+/// Find all memory area from the boot information, and seed the physical
+/// page allocator with the available ones.
+pub fn find_kernel_areas(boot_info: &BootInfo)
 {
-    let boot_info = unsafe{
-        multiboot2::BootInformation::load(multiboot_info as *const BootInformationHeader)};
-    let binding_boot_info = boot_info.expect("Map info exists.");
-    let memory_map_tag = binding_boot_info.memory_map_tag();
+    // Available memory first, every other type is reserved afterwards in
+    // case the firmware reports overlapping areas.
+    for area in boot_info.mem_areas(){
+        if area.area_type == MemAreaType::Available{
+            phys_area_add(area.start, area.end);
+        }
+    }
+    for area in boot_info.mem_areas(){
+        if area.area_type != MemAreaType::Available{
+            phys_area_reserve(area.start, area.end);
+        }
+    }
+
+    // Kernel image.
+    let (kernel_start, kernel_end) = kernel_image_range();
+    println!("[+] Kernel: 0x{:x} - 0x{:x}", kernel_start, kernel_end);
+    phys_area_reserve(kernel_start, kernel_end);
+
+    // Boot information itself.
+    phys_area_reserve(boot_info.info_start, boot_info.info_end);
+
+    // Boot modules.
+    for module in boot_info.modules(){
+        phys_area_reserve(module.start, module.end);
+    }
 
-    let binding_memory_tag = memory_map_tag.expect("Map area exist.");
-    let memory_areas = binding_memory_tag.memory_areas();
-    for area in memory_areas{
-        println!("[+] start: 0x{:x}, end: 0x{:x}", area.start_address(), area.end_address());
+    // Memory above mem= is ignored.
+    if let Some(limit) = kernel_options().mem_limit{
+        println!("[+] Limit memory to 0x{:x}", limit);
+        phys_area_reserve(limit, usize::MAX - PAGE_SIZE + 1);
     }
 
-    // let kernel_sections = binding_boot_info.elf_sections();
-    // let binding_kernel_sections = kernel_sections.expect("Kernel sections.");
-    // for section in binding_kernel_sections{
-    //     println!("[+] start: {:x}, end: {:x}, flags: {:x}", section.start_address(), section.end_address(), section.flags());
-    // }
+    phys_mem_init();
+    println!("[+] Usable memory: {} KB", PHYS_AREAS.lock().total_size() / 1024);
 }
 
 
+/// Kernel address space: every physical memory area at PHYS_TO_VIRT_BASE,
+/// and the kernel image at KERN_MAPPING_OFFSET with per-section permissions.
 pub struct KernelLayout{
     page_table: PageTable,
 }
 
 impl KernelLayout{
-    // TODO: we should read the original segments from
-    // multiboot info.
-}
\ No newline at end of file
+    /// Build the kernel page table from boot information.
+    pub fn new(boot_info: &BootInfo) -> Option<Self>{
+        let mut layout: Self = Self{ page_table: PageTable::new()? };
+        // Kernel mappings are the same in every address space, so global.
+        let data_flags: PTEFlags = PTEFlags::new(PRESENT | WRITABLE | NO_EXECUTE | GLOBAL);
+        let mmio_flags: PTEFlags = PTEFlags::new(PRESENT | WRITABLE | NO_EXECUTE | WRITE_THROUGH | NO_CACHE | GLOBAL);
+
+        // Address spaces share the kernel half from the start.
+        layout.page_table.prealloc_kernel_half().ok()?;
+
+        // Low memory holds BIOS data, the VGA memory is uncached.
+        layout.map_direct(0, VGA_MEM_START, data_flags).ok()?;
+        layout.map_direct(VGA_MEM_START, VGA_MEM_END, mmio_flags).ok()?;
+        layout.map_direct(VGA_MEM_END, LOW_MEM_LIMIT, data_flags).ok()?;
+
+        // All areas reported by the bootloader. Reserved ones may be MMIO.
+        for area in boot_info.mem_areas(){
+            match area.area_type{
+                MemAreaType::Available | MemAreaType::AcpiReclaimable | MemAreaType::AcpiNvs => {
+                    layout.map_direct(area.start, area.end, data_flags).ok()?;
+                }
+                MemAreaType::Reserved => {
+                    if area.start >= LOW_MEM_LIMIT{
+                        layout.map_direct(area.start, area.end, mmio_flags).ok()?;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // Framebuffer set up by the bootloader.
+        if let Some(fb) = boot_info.framebuffer{
+            layout.map_direct(fb.addr, fb.addr + fb.pitch * fb.height, mmio_flags).ok()?;
+        }
+
+        // Kernel image, section by section.
+        let mut mapped: bool = false;
+        for section in boot_info.elf_sections(){
+            if !section.is_contain(ELF_SECTION_ALLOCATED) || section.start >= section.end{
+                continue;
+            }
+
+            let mut flags: u64 = PRESENT;
+            if section.is_contain(ELF_SECTION_WRITABLE){
+                flags |= WRITABLE;
+            }
+            if !section.is_contain(ELF_SECTION_EXECUTABLE){
+                flags |= NO_EXECUTE;
+            }
+
+            // Boot sections are linked at their physical address, they
+            // stay reachable through their higher-half alias.
+            let start: usize = page_align_down(section.start);
+            let end: usize = page_align_up(section.end);
+            let phys_start: usize = if start >= KERN_MAPPING_OFFSET { start - KERN_MAPPING_OFFSET } else { start };
+            layout.map_image(phys_start + KERN_MAPPING_OFFSET, phys_start, end - start, flags).ok()?;
+            mapped = true;
+        }
+
+        // Without ELF sections, fall back to the section bounds from the
+        // linker script.
+        if !mapped{
+            for (start, end, flags) in kernel_image_sections(){
+                let phys_start: usize = start - KERN_MAPPING_OFFSET;
+                layout.map_image(start, phys_start, page_align_up(end) - start, flags).ok()?;
+            }
+        }
+
+        Some(layout)
+    }
+
+    /// Map physical [start, end) into the direct map, with huge pages where
+    /// possible. Areas may overlap, pages mapped already keep their flags.
+    fn map_direct(&mut self, start: usize, end: usize, flags: PTEFlags) -> Result<(), MapError>{
+        let mut paddr: usize = page_align_down(start);
+        let end: usize = page_align_up(end);
+        while paddr < end{
+            match self.page_table.map_largest(phys_to_virt(PhysAddr::from(paddr)), PhysAddr::from(paddr),
+                end - paddr, flags){
+                Ok(page_size) => { paddr += page_size.size(); }
+                Err(MapError::AlreadyMapped) => { paddr += PAGE_SIZE; }
+                Err(err) => { return Err(err); }
+            }
+        }
+        Ok(())
+    }
+
+    /// Map kernel image pages. Sections sharing a page get both permissions.
+    fn map_image(&mut self, virt_start: usize, phys_start: usize, size: usize, flags: u64) -> Result<(), MapError>{
+        let mut offset: usize = 0;
+        while offset < size{
+            let vaddr: VirtAddr = VirtAddr::from(virt_start + offset);
+            let mut page_flags: u64 = flags | GLOBAL;
+            if let Some((_, old_flags)) = self.page_table.translate(vaddr){
+                let old_flags: u64 = old_flags.as_u64();
+                page_flags = ((flags | old_flags) & !NO_EXECUTE) | (flags & old_flags & NO_EXECUTE);
+                self.page_table.unmap(vaddr);
+            }
+            self.page_table.map(vaddr, PhysAddr::from(phys_start + offset), PTEFlags::new(page_flags))?;
+            offset += PAGE_SIZE;
+        }
+        Ok(())
+    }
+
+    /// Kernel page table.
+    pub fn page_table(&mut self) -> &mut PageTable{
+        &mut self.page_table
+    }
+
+    /// Switch to this layout. No-execute and write protection are turned on
+    /// first, so section permissions apply to the kernel too.
+    pub fn enable(&self){
+        wrmsr(MSR_EFER, rdmsr(MSR_EFER) | EFER_NXE);
+        lcr0(rcr0() | CR0_WP);
+        self.page_table.enable();
+    }
+}
+
+// Kernel address space, set up once physical memory allocation works.
+pub static KERNEL_LAYOUT: Mutex<Option<KernelLayout>> = Mutex::new(None);
+
+/// Build and enable the kernel address space. The boot page table, with its
+/// identity mapping, is no longer used afterwards.
+pub fn kernel_layout_init(boot_info: &BootInfo){
+    match KernelLayout::new(boot_info){
+        Some(layout) => {
+            layout.enable();
+            tlb_init();
+            *KERNEL_LAYOUT.lock() = Some(layout);
+            println!("[+] Kernel page table enabled, direct map at 0x{:x}", PHYS_TO_VIRT_BASE);
+            if kernel_options().loglevel >= LogLevel::Debug{
+                KERNEL_LAYOUT.lock().as_ref().unwrap().page_table.dump();
+            }
+        }
+        _ => {
+            println!("[Err] Failed build kernel page table.");
+            return ;
+        }
+    }
+
+    // Memory above the boot direct map is reachable now.
+    phys_mem_init_high();
+}
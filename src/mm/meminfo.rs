@@ -0,0 +1,63 @@
+#![allow(dead_code)]
+
+use crate::println;
+
+use super::frame::{frames_of_type, frame_table_len, PageType};
+use super::heap::KERNEL_HEAP;
+use super::phys_page::{FRAME_ALLOCATOR, PAGE_SIZE};
+
+/// Memory usage in bytes.
+#[derive(Clone, Copy)]
+pub struct MemInfo{
+    // Frames covered by the frame table.
+    pub total: usize,
+    // Frames in the page allocator.
+    pub free: usize,
+    // Frames never given to the page allocator: firmware, holes, kernel
+    // image, boot modules.
+    pub reserved: usize,
+    pub kernel: usize,
+    pub page_table: usize,
+    pub anon: usize,
+    pub dma: usize,
+    pub slab: usize,
+    // Frames owned by the kernel heap.
+    pub heap: usize,
+    // Heap bytes handed out, including rounding.
+    pub heap_allocated: usize,
+    // Heap bytes requested by callers.
+    pub heap_user: usize,
+}
+
+/// Collect current memory usage.
+pub fn meminfo() -> MemInfo{
+    let (heap_user, heap_allocated, _) = KERNEL_HEAP.stats();
+    MemInfo{
+        total: frame_table_len() * PAGE_SIZE,
+        free: FRAME_ALLOCATOR.lock().free_pages() * PAGE_SIZE,
+        reserved: frames_of_type(PageType::Reserved) * PAGE_SIZE,
+        kernel: frames_of_type(PageType::Kernel) * PAGE_SIZE,
+        page_table: frames_of_type(PageType::PageTable) * PAGE_SIZE,
+        anon: frames_of_type(PageType::Anon) * PAGE_SIZE,
+        dma: frames_of_type(PageType::Dma) * PAGE_SIZE,
+        slab: frames_of_type(PageType::Slab) * PAGE_SIZE,
+        heap: frames_of_type(PageType::Heap) * PAGE_SIZE,
+        heap_allocated: heap_allocated,
+        heap_user: heap_user,
+    }
+}
+
+/// Print current memory usage.
+pub fn print_meminfo(){
+    let info: MemInfo = meminfo();
+    println!("[+] MemTotal:     {:>10} KB", info.total / 1024);
+    println!("[+] MemFree:      {:>10} KB", info.free / 1024);
+    println!("[+] Reserved:     {:>10} KB", info.reserved / 1024);
+    println!("[+] Kernel:       {:>10} KB", info.kernel / 1024);
+    println!("[+] PageTables:   {:>10} KB", info.page_table / 1024);
+    println!("[+] Anon:         {:>10} KB", info.anon / 1024);
+    println!("[+] Dma:          {:>10} KB", info.dma / 1024);
+    println!("[+] Slab:         {:>10} KB", info.slab / 1024);
+    println!("[+] Heap:         {:>10} KB ({} bytes used, {} requested)", info.heap / 1024,
+        info.heap_allocated, info.heap_user);
+}
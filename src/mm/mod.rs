@@ -1,4 +1,14 @@
 pub mod phys_page;
+pub mod buddy;
+pub mod frame;
+pub mod heap;
+pub mod slab;
+pub mod meminfo;
 pub mod page_table_entry;
 pub mod page_table;
-pub mod layout;
\ No newline at end of file
+pub mod layout;
+pub mod kstack;
+pub mod tlb;
+pub mod page_walk;
+pub mod address_space;
+pub mod fault;
\ No newline at end of file
@@ -3,13 +3,18 @@
 #![allow(unused_doc_comments)]
 
 use core::arch::asm;
+use core::mem::ManuallyDrop;
 use core::ops::{Index, IndexMut};
 use core::slice::{from_raw_parts, from_raw_parts_mut};
 
-use crate::println;
-
-use super::page_table_entry::{PhysAddr, VirtAddr, PTE, PTEFlags, PRESENT, WRITABLE};
-use super::phys_page::{phys_page_alloc, PAGE_SIZE};
+use super::page_table_entry::{PhysAddr, VirtAddr, PTE, PTEFlags, HUGE_PAGE, NO_EXECUTE, PROTECT_FLAGS, USER,
+    WRITABLE};
+use super::tlb::{flush_tlb_range, load_page_table, pcid_alloc, pcid_invalidate, CR3_PCID_MASK};
+use crate::asms::cpuid::cpu_has_1g_pages;
+use super::phys_page::{phys_page_put, phys_to_virt, virt_to_phys, PAGE_SIZE};
+use super::slab::{slab_cache_create, SlabCache};
+use super::frame::{page_of, PageType};
+use crate::utils::linked_list::LinkedList;
 
 /// Store value to cr0.
 #[cfg(target_arch = "x86_64")]
@@ -21,10 +26,12 @@ pub fn lcr0(mut _val: usize){
 
 /// Read value from cr0.
 #[cfg(target_arch = "x86_64")]
-pub fn rcr0(mut _val: usize){
+pub fn rcr0() -> usize{
+    let val: usize;
     unsafe{
-        asm!("mov {}, cr0", out(reg) _val);
+        asm!("mov {}, cr0", out(reg) val);
     }
+    val
 }
 
 /// Store value to cr3.
@@ -37,20 +44,63 @@ pub fn lcr3(mut _val: usize){
 
 /// Read value from cr3.
 #[cfg(target_arch = "x86_64")]
-pub fn rcr3(mut _val: usize){
+pub fn rcr3() -> usize{
+    let val: usize;
+    unsafe{
+        asm!("mov {}, cr3", out(reg) val);
+    }
+    val
+}
+
+/// Read value from cr2, the last page fault address.
+#[cfg(target_arch = "x86_64")]
+pub fn rcr2() -> usize{
+    let val: usize;
     unsafe{
-        asm!("mov {}, cr3", out(reg) _val);
+        asm!("mov {}, cr2", out(reg) val);
     }
+    val
 }
 
-/// Default linearly mapping offset as 256MB.
-pub const KERN_MAPPING_OFFSET: usize = 0x10000000;
-/// Default mapping from physical to virtual address.
+/// Store value to cr4.
+#[cfg(target_arch = "x86_64")]
+pub fn lcr4(mut _val: usize){
+    unsafe{
+        asm!("mov cr4, {}", in(reg) _val);
+    }
+}
+
+/// Read value from cr4.
+#[cfg(target_arch = "x86_64")]
+pub fn rcr4() -> usize{
+    let val: usize;
+    unsafe{
+        asm!("mov {}, cr4", out(reg) val);
+    }
+    val
+}
+
+/// CR0 write protect, kernel honors read-only pages.
+pub const CR0_WP: usize = 1 << 16;
+/// CR4 global pages.
+pub const CR4_PGE: usize = 1 << 7;
+/// CR4 process context identifiers.
+pub const CR4_PCIDE: usize = 1 << 17;
+
+/// Kernel image is linked in the top 2GB, physical address 0 is mapped here.
+pub const KERN_MAPPING_OFFSET: usize = 0xffffffff80000000;
+/// Kernel image mapping from physical to virtual address.
 #[inline]
 pub fn kernel_phys_to_virt(paddr: PhysAddr) -> VirtAddr{
     VirtAddr::from(paddr.to_usize() + KERN_MAPPING_OFFSET)
 }
 
+/// Kernel image mapping from virtual to physical address.
+#[inline]
+pub fn kernel_virt_to_phys(vaddr: VirtAddr) -> PhysAddr{
+    PhysAddr::from(vaddr.to_usize() - KERN_MAPPING_OFFSET)
+}
+
 /// Identical mapping.
 #[inline]
 pub fn identical_phys_to_virt(paddr: PhysAddr) -> VirtAddr{
@@ -60,8 +110,122 @@ pub fn identical_phys_to_virt(paddr: PhysAddr) -> VirtAddr{
 /// Every page table holds 512 entries.
 pub const NUM_PAGE_ENTRY: usize = 512;
 
+/// Sizes of huge pages.
+pub const HUGE_PAGE_SIZE_2M: usize = 0x200000;
+pub const HUGE_PAGE_SIZE_1G: usize = 0x40000000;
+
+/// Page sizes, mapped by a level 1, 2 or 3 entry.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PageSize{
+    Size4K,
+    Size2M,
+    Size1G,
+}
+
+impl PageSize{
+    /// Size in bytes.
+    pub const fn size(&self) -> usize{
+        match self{
+            PageSize::Size4K => PAGE_SIZE,
+            PageSize::Size2M => HUGE_PAGE_SIZE_2M,
+            PageSize::Size1G => HUGE_PAGE_SIZE_1G,
+        }
+    }
+
+    /// Next smaller page size.
+    pub const fn smaller(&self) -> Option<PageSize>{
+        match self{
+            PageSize::Size4K => None,
+            PageSize::Size2M => Some(PageSize::Size4K),
+            PageSize::Size1G => Some(PageSize::Size2M),
+        }
+    }
+}
+
+lazy_static!{
+    // Whether 1GB pages can be used.
+    static ref HAS_1G_PAGES: bool = cpu_has_1g_pages();
+}
+
+lazy_static!{
+    // Cache of page table pages.
+    static ref PAGE_TABLE_CACHE: Option<&'static SlabCache> =
+        slab_cache_create("page_table", PAGE_SIZE, PAGE_SIZE, None);
+}
+
+/// Allocate a zeroed page for a page table.
+pub fn page_table_alloc() -> Option<PhysAddr>{
+    let cache: &'static SlabCache = (*PAGE_TABLE_CACHE)?;
+    let table: *mut u8 = cache.alloc()?;
+    unsafe{
+        core::ptr::write_bytes(table, 0, PAGE_SIZE);
+    }
+    let paddr: PhysAddr = virt_to_phys(VirtAddr::from(table));
+    if let Some(page) = page_of(paddr){
+        page.set_type(PageType::PageTable);
+    }
+    Some(paddr)
+}
+
+/// Free a page of a page table.
+pub fn page_table_free(paddr: PhysAddr){
+    if let Some(page) = page_of(paddr){
+        page.set_type(PageType::Slab);
+    }
+    if let Some(cache) = *PAGE_TABLE_CACHE{
+        cache.free(phys_to_virt(paddr).to_mut_ptr() as *mut u8);
+    }
+}
+
+/// Why a mapping cannot be made.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MapError{
+    // No memory for a page table.
+    OutOfMemory,
+    // Something is mapped at the virtual address already.
+    AlreadyMapped,
+    // Addresses or size are not page aligned.
+    Misaligned,
+    // Virtual address is not canonical.
+    NonCanonical,
+    // Page size is not supported by the CPU.
+    Unsupported,
+    // Part of the range is not mapped.
+    NotMapped,
+}
+
+/// First address of the kernel half. Its upper tables are shared by every
+/// page table, and never freed through one of them.
+pub const KERNEL_HALF_START: usize = 0xffff800000000000;
+/// Number of level 4 entries of the user half.
+pub const NUM_USER_L4_ENTRY: usize = NUM_PAGE_ENTRY / 2;
+
 pub struct PageTable{
     base: PhysAddr,
+    // Process context id used when this table is loaded.
+    pcid: usize,
+    // Mapped frames of the user half are freed with the table.
+    owns_frames: bool,
+}
+
+/// Free the user half: every table below it, and mapped frames if the page
+/// table owns them. The kernel half is left to the kernel page table.
+impl Drop for PageTable{
+    fn drop(&mut self){
+        // Freeing tables under the CPU would be fatal, leak them instead.
+        if self.is_active(){
+            return ;
+        }
+        let l4_table = self.to_mut_ptes();
+        for pte in l4_table[..NUM_USER_L4_ENTRY].iter_mut(){
+            if pte.is_present(){
+                self.free_table(*pte, 3, self.owns_frames);
+            }
+            pte.set_unused();
+        }
+        pcid_invalidate(self.base, self.pcid);
+        page_table_free(self.base);
+    }
 }
 
 /// Provide index trait for page table.
@@ -85,10 +249,10 @@ impl IndexMut<usize> for PageTable{
 impl PageTable{
     /// Create a new page table.
     pub fn new() -> Option<Self>{
-        let result = phys_page_alloc();
+        let result = page_table_alloc();
         match result{
             Some(phys_page)=>{
-                Some(Self{base: phys_page})
+                Some(Self{base: phys_page, pcid: pcid_alloc(), owns_frames: false})
             }
             _ => {
                 None
@@ -99,44 +263,162 @@ impl PageTable{
     /// Convert page table to a pte array.
     pub fn to_ptes(&self) -> &'static [PTE]{
         unsafe{
-            from_raw_parts(self.base.to_raw_ptr() as *mut PTE, NUM_PAGE_ENTRY)
+            from_raw_parts(phys_to_virt(self.base).to_raw_ptr() as *mut PTE, NUM_PAGE_ENTRY)
         }
     }
 
     /// Convert page table to a mutable pte array.
     pub fn to_mut_ptes(&self) -> &'static mut [PTE]{
         unsafe{
-            from_raw_parts_mut(self.base.to_mut_ptr() as *mut PTE, NUM_PAGE_ENTRY)
+            from_raw_parts_mut(phys_to_virt(self.base).to_mut_ptr() as *mut PTE, NUM_PAGE_ENTRY)
         }
     }
 
     /// Enable this page table.
     pub fn enable(&self){
-        lcr3(self.base.to_usize());
+        load_page_table(self.base, self.pcid);
     }
 
-    /// Swap current page table.
-    pub fn swap(&self) -> Self{
-        let mut curr_page_table: usize = 0;
-        rcr3(curr_page_table);
+    /// Swap current page table. The previous one is not owned, so it is
+    /// never freed.
+    pub fn swap(&self) -> ManuallyDrop<Self>{
+        let curr_page_table: usize = rcr3();
         self.enable();
 
-        Self{
-            base: PhysAddr::from(curr_page_table)
+        ManuallyDrop::new(Self{
+            base: PhysAddr::from(curr_page_table & !CR3_PCID_MASK),
+            pcid: curr_page_table & CR3_PCID_MASK,
+            owns_frames: false,
+        })
+    }
+
+    /// Give every level 4 entry of the kernel half a table, so page tables
+    /// sharing the kernel half see later kernel mappings too.
+    pub fn prealloc_kernel_half(&mut self) -> Result<(), MapError>{
+        let l4_table = self.to_mut_ptes();
+        for pte in l4_table[NUM_USER_L4_ENTRY..].iter_mut(){
+            if pte.is_unused(){
+                *pte = self.create_next_table().ok_or(MapError::OutOfMemory)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Use the kernel half of another page table.
+    pub fn share_kernel_half(&mut self, kernel: &PageTable){
+        let l4_table = self.to_mut_ptes();
+        l4_table[NUM_USER_L4_ENTRY..].copy_from_slice(&kernel.to_ptes()[NUM_USER_L4_ENTRY..]);
+    }
+
+    /// Choose whether frames mapped in the user half are freed with the table.
+    pub fn set_owns_frames(&mut self, owns_frames: bool){
+        self.owns_frames = owns_frames;
+    }
+
+    /// Free a table of the given level, the tables below it, and with
+    /// free_frames the frames it maps.
+    fn free_table(&self, table_pte: PTE, level: usize, free_frames: bool){
+        let table = self.next_mut_table_as_array(table_pte);
+        for pte in table.iter(){
+            if !pte.is_present(){
+                continue;
+            }
+            if level == 1 || pte.is_contain(HUGE_PAGE){
+                if free_frames{
+                    let page_size: usize = PAGE_SIZE << (9 * (level - 1));
+                    phys_page_put(PhysAddr::from(pte.phys_addr().to_usize() & !(page_size - 1)));
+                }
+            }
+            else{
+                self.free_table(*pte, level - 1, free_frames);
+            }
+        }
+        page_table_free(table_pte.phys_addr());
+    }
+
+    /// Unlink tables under [start, end) of a level table that map nothing
+    /// anymore, and chain them on freed. They are freed by the caller once
+    /// the TLB is flushed. Tables of the kernel half are shared by every
+    /// page table and never reclaimed. Return whether the table is empty
+    /// afterwards.
+    fn reclaim_tables(&self, table: &mut [PTE], level: usize, start: usize, end: usize,
+                      freed: &mut LinkedList) -> bool{
+        if level > 1{
+            let span: usize = 1 << (12 + 9 * (level - 1));
+            let mut vaddr: usize = start;
+            while vaddr < end && vaddr < KERNEL_HALF_START{
+                let next: usize = (vaddr & !(span - 1)).checked_add(span).unwrap_or(usize::MAX);
+                let pte: &mut PTE = &mut table[(vaddr / span) & (NUM_PAGE_ENTRY - 1)];
+                if let Some(next_table) = self.next_table(*pte){
+                    if self.reclaim_tables(next_table, level - 1, vaddr, next.min(end), freed){
+                        // The table is all zero, so its first entry holds the link.
+                        freed.push(next_table.as_mut_ptr() as *mut usize);
+                        pte.set_unused();
+                    }
+                }
+                vaddr = next;
+            }
+        }
+        table.iter().all(|pte| pte.is_unused())
+    }
+
+    /// Check whether this page table is loaded.
+    pub fn is_active(&self) -> bool{
+        rcr3() & !CR3_PCID_MASK == self.base.to_usize()
+    }
+
+    /// Check whether the loaded page table translates vaddr through this
+    /// one, which is the case for the shared kernel half.
+    fn is_loaded_for(&self, vaddr: VirtAddr) -> bool{
+        if self.is_active(){
+            return true;
         }
+        let active: &[PTE] = unsafe{
+            from_raw_parts(phys_to_virt(PhysAddr::from(rcr3() & !CR3_PCID_MASK)).to_raw_ptr() as *const PTE,
+                NUM_PAGE_ENTRY)
+        };
+        let l4_index: usize = vaddr.l4_index();
+        let pte: PTE = self.to_ptes()[l4_index];
+        pte.is_present() && active[l4_index].phys_addr() == pte.phys_addr()
+    }
+
+    /// Drop stale translations of [start, end) after entries changed.
+    pub fn flush_range(&self, start: VirtAddr, end: VirtAddr){
+        if start >= end{
+            return ;
+        }
+        if self.is_loaded_for(start){
+            flush_tlb_range(start, end);
+        }
+        if !self.is_active(){
+            pcid_invalidate(self.base, self.pcid);
+        }
+    }
+
+    /// Drop stale translations of one page.
+    pub fn flush_page(&self, vaddr: VirtAddr, page_size: PageSize){
+        self.flush_range(vaddr, vaddr + page_size.size());
     }
 
     /// Get next level table.
     fn next_mut_table_as_array(&self, pte: PTE) -> &'static mut [PTE]{
         let next_table_addr: PhysAddr = pte.phys_addr();
         unsafe{
-            from_raw_parts_mut(next_table_addr.to_mut_ptr() as *mut PTE, NUM_PAGE_ENTRY)
+            from_raw_parts_mut(phys_to_virt(next_table_addr).to_mut_ptr() as *mut PTE, NUM_PAGE_ENTRY)
+        }
+    }
+
+    /// Get next level table if pte points to one.
+    pub(super) fn next_table(&self, pte: PTE) -> Option<&'static mut [PTE]>{
+        if !pte.is_present() || pte.is_contain(HUGE_PAGE){
+            return None;
         }
+        Some(self.next_mut_table_as_array(pte))
     }
 
     /// Create next level table.
     fn create_next_table(&self) -> Option<PTE>{
-        let new_table = phys_page_alloc();
+        let new_table = page_table_alloc();
         match new_table{
             Some(next_table) => {
                 Some(PTE::new_table_entry(next_table))
@@ -145,119 +427,394 @@ impl PageTable{
         }
     }
 
-    /// Create and set up next level table. Return pte points to next table.
-    fn setup_next_table(&self, pte: &mut PTE, pte_index: usize){
+    /// Get next level table, create it if pte is unused. User pages need
+    /// the user bit on every level.
+    fn setup_next_table_as_array(&self, pte: &mut PTE, flags: PTEFlags) -> Result<&'static mut [PTE], MapError>{
         if pte.is_unused(){
-            let result = self.create_next_table();
-            match result{
-                Some(new_pte) => {
-                    *pte = new_pte;
-                }
-                _ => {
-                    println!("[Err] Create next level page table failed.");
-                    return ;
-                }
+            *pte = self.create_next_table().ok_or(MapError::OutOfMemory)?;
+        }
+        else if !pte.is_present() || pte.is_contain(HUGE_PAGE){
+            return Err(MapError::AlreadyMapped);
+        }
+        if flags.is_contain(USER) && !pte.is_contain(USER){
+            pte.set_flags(pte.flags() | USER);
+        }
+        Ok(self.next_mut_table_as_array(*pte))
+    }
+
+    /// Get the entry that maps vaddr, with the size of its page, if all
+    /// upper tables are present.
+    pub fn get_pte(&self, vaddr: VirtAddr) -> Option<(&'static mut PTE, PageSize)>{
+        if !vaddr.is_canonical(){
+            return None;
+        }
+        let l4_table: &[PTE] = self.to_ptes();
+        let l3_table = self.next_table(l4_table[vaddr.l4_index()])?;
+        let l3_pte: &'static mut PTE = &mut l3_table[vaddr.l3_index()];
+        if l3_pte.is_present() && l3_pte.is_contain(HUGE_PAGE){
+            return Some((l3_pte, PageSize::Size1G));
+        }
+        let l2_table = self.next_table(*l3_pte)?;
+        let l2_pte: &'static mut PTE = &mut l2_table[vaddr.l2_index()];
+        if l2_pte.is_present() && l2_pte.is_contain(HUGE_PAGE){
+            return Some((l2_pte, PageSize::Size2M));
+        }
+        let l1_table = self.next_table(*l2_pte)?;
+        Some((&mut l1_table[vaddr.l1_index()], PageSize::Size4K))
+    }
+
+    /// Get physical address and flags of a mapped virtual address. The huge
+    /// page bit is not part of the flags.
+    pub fn translate(&self, vaddr: VirtAddr) -> Option<(PhysAddr, PTEFlags)>{
+        let (pte, page_size) = self.get_pte(vaddr)?;
+        if !pte.is_present(){
+            return None;
+        }
+        let mask: usize = page_size.size() - 1;
+        let paddr: PhysAddr = PhysAddr::from((pte.phys_addr().to_usize() & !mask) | (vaddr.to_usize() & mask));
+        Some((paddr, pte.flags() & !HUGE_PAGE))
+    }
+
+    /// Size of the page mapping vaddr.
+    pub fn page_size(&self, vaddr: VirtAddr) -> Option<PageSize>{
+        match self.get_pte(vaddr)?{
+            (pte, page_size) if pte.is_present() => Some(page_size),
+            _ => None,
+        }
+    }
+
+    /// Largest page size usable for both addresses with size bytes left.
+    pub fn largest_page_size(vaddr: VirtAddr, paddr: PhysAddr, size: usize) -> PageSize{
+        let addrs: usize = vaddr.to_usize() | paddr.to_usize();
+        if *HAS_1G_PAGES && addrs & (HUGE_PAGE_SIZE_1G - 1) == 0 && size >= HUGE_PAGE_SIZE_1G{
+            PageSize::Size1G
+        }
+        else if addrs & (HUGE_PAGE_SIZE_2M - 1) == 0 && size >= HUGE_PAGE_SIZE_2M{
+            PageSize::Size2M
+        }
+        else{
+            PageSize::Size4K
+        }
+    }
+
+    /// Map a physical page of the given size to a virtual page.
+    pub fn map_page(&mut self, vaddr: VirtAddr, paddr: PhysAddr, flags: PTEFlags, page_size: PageSize)
+        -> Result<(), MapError>{
+        self.set_page(vaddr, paddr, flags, page_size)?;
+        self.flush_page(vaddr, page_size);
+        Ok(())
+    }
+
+    /// Set the entry of a page, without TLB invalidation.
+    fn set_page(&mut self, vaddr: VirtAddr, paddr: PhysAddr, flags: PTEFlags, page_size: PageSize)
+        -> Result<(), MapError>{
+        if !vaddr.is_canonical(){
+            return Err(MapError::NonCanonical);
+        }
+        let mask: usize = page_size.size() - 1;
+        if vaddr.to_usize() & mask != 0 || paddr.to_usize() & mask != 0{
+            return Err(MapError::Misaligned);
+        }
+        if page_size == PageSize::Size1G && !*HAS_1G_PAGES{
+            return Err(MapError::Unsupported);
+        }
+
+        let l4_table = self.to_mut_ptes();
+        let l3_table = self.setup_next_table_as_array(&mut l4_table[vaddr.l4_index()], flags)?;
+        let (leaf, flags): (&mut PTE, PTEFlags) = if page_size == PageSize::Size1G{
+            (&mut l3_table[vaddr.l3_index()], flags | HUGE_PAGE)
+        }
+        else{
+            let l2_table = self.setup_next_table_as_array(&mut l3_table[vaddr.l3_index()], flags)?;
+            if page_size == PageSize::Size2M{
+                (&mut l2_table[vaddr.l2_index()], flags | HUGE_PAGE)
+            }
+            else{
+                let l1_table = self.setup_next_table_as_array(&mut l2_table[vaddr.l2_index()], flags)?;
+                (&mut l1_table[vaddr.l1_index()], flags)
             }
-        } else {
-            pte.set_flags(PTEFlags::new(PRESENT | WRITABLE));
+        };
+
+        if !leaf.is_unused(){
+            return Err(MapError::AlreadyMapped);
         }
+        *leaf = PTE::new_page_entry(paddr, flags);
+        Ok(())
     }
 
-    /// Create and set up next level table. Return next level table as an array.
-    fn setup_next_table_as_array(&self, pte: &mut PTE) -> Option<&'static mut [PTE]>{
-        if pte.is_unused(){
-            let result = self.create_next_table();
-            match result{
-                Some(new_pte) => {
-                    *pte = new_pte;
-                    Some(self.next_mut_table_as_array(new_pte))
-                }
-                _ => {
-                    return None;
+    /// Map a physical page to a virtual page in this page table.
+    pub fn map(&mut self, vaddr: VirtAddr, paddr: PhysAddr, flags: PTEFlags) -> Result<(), MapError>{
+        self.map_page(vaddr, paddr, flags, PageSize::Size4K)
+    }
+
+    /// Map one page, as large as alignment and size bytes left allow. Smaller
+    /// pages are tried if part of a huge page is in use already.
+    pub fn map_largest(&mut self, vaddr: VirtAddr, paddr: PhysAddr, size: usize, flags: PTEFlags)
+        -> Result<PageSize, MapError>{
+        let page_size: PageSize = self.set_largest(vaddr, paddr, size, flags)?;
+        self.flush_page(vaddr, page_size);
+        Ok(page_size)
+    }
+
+    /// Set the entry of the largest page that fits, without TLB invalidation.
+    fn set_largest(&mut self, vaddr: VirtAddr, paddr: PhysAddr, size: usize, flags: PTEFlags)
+        -> Result<PageSize, MapError>{
+        let mut page_size: PageSize = Self::largest_page_size(vaddr, paddr, size);
+        loop{
+            match self.set_page(vaddr, paddr, flags, page_size){
+                Ok(_) => { return Ok(page_size); }
+                Err(MapError::AlreadyMapped) if page_size != PageSize::Size4K => {
+                    page_size = page_size.smaller().unwrap_or(PageSize::Size4K);
                 }
+                Err(err) => { return Err(err); }
             }
-        }else{
-            Some(self.next_mut_table_as_array(*pte))
         }
     }
 
-    /// Get level 1 page table entry.
-    pub fn get_level1_pte(&self, vaddr: VirtAddr) -> &mut PTE{
-        let l4_table: & [PTE] = self.to_ptes();
-        let l4_index: usize = vaddr.l4_index();
-        let l4_pte = l4_table[l4_index];
+    /// Split the huge page mapping vaddr into pages of the next smaller size,
+    /// with the same flags. Nothing happens to 4KB or unmapped pages.
+    pub fn split(&mut self, vaddr: VirtAddr) -> Result<(), MapError>{
+        if let Some(page_size) = self.split_entry(vaddr)?{
+            let start: VirtAddr = VirtAddr::from(vaddr.to_usize() & !(page_size.size() - 1));
+            self.flush_page(start, page_size);
+        }
+        Ok(())
+    }
 
-        let l3_table = self.next_mut_table_as_array(l4_pte);
-        let l3_index: usize = vaddr.l3_index();
-        let l3_pte = l3_table[l3_index];
+    /// Split a huge page without TLB invalidation. Return the size of the
+    /// page that was split.
+    fn split_entry(&mut self, vaddr: VirtAddr) -> Result<Option<PageSize>, MapError>{
+        let (pte, page_size) = match self.get_pte(vaddr){
+            Some((pte, page_size)) if pte.is_present() => (pte, page_size),
+            _ => { return Ok(None); }
+        };
+        let smaller: PageSize = match page_size.smaller(){
+            Some(smaller) => smaller,
+            _ => { return Ok(None); }
+        };
+
+        let table_paddr: PhysAddr = page_table_alloc().ok_or(MapError::OutOfMemory)?;
+        let table_pte: PTE = if pte.is_contain(USER){
+            PTE::new_user_table_entry(table_paddr)
+        }
+        else{
+            PTE::new_table_entry(table_paddr)
+        };
+        let table = self.next_mut_table_as_array(table_pte);
+
+        let base: usize = pte.phys_addr().to_usize() & !(page_size.size() - 1);
+        let mut flags: PTEFlags = pte.flags();
+        if smaller == PageSize::Size4K{
+            flags &= !HUGE_PAGE;
+        }
+        for (index, entry) in table.iter_mut().enumerate(){
+            *entry = PTE::new_page_entry(PhysAddr::from(base + index * smaller.size()), flags);
+        }
+        *pte = table_pte;
+        Ok(Some(page_size))
+    }
 
-        let l2_table = self.next_mut_table_as_array(l3_pte);
-        let l2_index: usize = vaddr.l2_index();
-        let l2_pte: PTE = l2_table[l2_index];
+    /// Change the protection of the mapped region [virt_start, virt_start + size)
+    /// to the PROTECT_FLAGS part of flags. Huge pages only partly in the region
+    /// are split. Nothing changes if part of the region is not mapped.
+    pub fn protect_region(&mut self, virt_start: VirtAddr, size: usize, flags: PTEFlags) -> Result<(), MapError>{
+        if !virt_start.is_page_aligned() || size & (PAGE_SIZE - 1) != 0{
+            return Err(MapError::Misaligned);
+        }
+        let end: usize = virt_start.to_usize() + size;
 
-        let l1_table = self.next_mut_table_as_array(l2_pte);
-        let l1_index: usize = vaddr.l1_index();
+        // Check first, so a failure leaves the region as it was.
+        let mut vaddr: usize = virt_start.to_usize();
+        while vaddr < end{
+            let page_size: PageSize = self.page_size(VirtAddr::from(vaddr)).ok_or(MapError::NotMapped)?;
+            vaddr = (vaddr & !(page_size.size() - 1)) + page_size.size();
+        }
 
-        &mut l1_table[l1_index]
+        let mut result: Result<(), MapError> = Ok(());
+        let mut vaddr: usize = virt_start.to_usize();
+        while vaddr < end{
+            let (pte, page_size) = match self.get_pte(VirtAddr::from(vaddr)){
+                Some(found) => found,
+                _ => { break; }
+            };
+            if vaddr & (page_size.size() - 1) != 0 || vaddr + page_size.size() > end{
+                if let Err(err) = self.split_entry(VirtAddr::from(vaddr)){
+                    result = Err(err);
+                    break;
+                }
+                continue;
+            }
+            pte.update_flags(PROTECT_FLAGS, flags);
+            if flags.is_contain(USER){
+                self.set_user_path(VirtAddr::from(vaddr));
+            }
+            vaddr += page_size.size();
+        }
+        self.flush_range(virt_start, VirtAddr::from(end));
+        result
     }
 
-    /// Get physical address.
-    pub fn retrieve(&self, vaddr: VirtAddr) -> PhysAddr{
-        let pte: &mut PTE = self.get_level1_pte(vaddr);
-        let base: PhysAddr = pte.phys_addr();
-        PhysAddr::from(base.to_usize() | vaddr.offset())
+    /// Change the protection of the page mapping vaddr.
+    pub fn protect(&mut self, vaddr: VirtAddr, flags: PTEFlags) -> Result<(), MapError>{
+        self.protect_region(VirtAddr::from(vaddr.to_usize() & !(PAGE_SIZE - 1)), PAGE_SIZE, flags)
     }
 
-    /// Map physical to virtual address in this page table.
-    /// TODO: WE MUST ALIGN ADDRESSES!
-    pub fn map(&mut self, vaddr: VirtAddr, paddr: PhysAddr, flags: PTEFlags){
-
-        let l4_table = self.to_mut_ptes();
-        let l4_index: usize = vaddr.l4_index();
-        let l4_pte: &mut PTE = &mut l4_table[l4_index];
-        self.setup_next_table(l4_pte, l4_index);
+    /// Set the user bit on the upper entries leading to vaddr.
+    fn set_user_path(&mut self, vaddr: VirtAddr){
+        let mut table: &'static mut [PTE] = self.to_mut_ptes();
+        for level in [4, 3, 2]{
+            let pte: &mut PTE = &mut table[vaddr.index(level)];
+            if !pte.is_present() || pte.is_contain(HUGE_PAGE){
+                return ;
+            }
+            if !pte.is_contain(USER){
+                pte.set_flags(pte.flags() | USER);
+            }
+            table = self.next_mut_table_as_array(*pte);
+        }
+    }
 
-        let l3_table = self.next_mut_table_as_array(*l4_pte);
-        let l3_index: usize = vaddr.l3_index();
-        let l3_pte: &mut PTE = &mut l3_table[l3_index];
-        self.setup_next_table(l3_pte, l3_index);
+    /// Effective permissions of a mapped address. Upper entries restrict the
+    /// page: it is writable or user only if every level is, and no-execute
+    /// if any level is.
+    pub fn query(&self, vaddr: VirtAddr) -> Option<PTEFlags>{
+        if !vaddr.is_canonical(){
+            return None;
+        }
+        let mut table: &'static [PTE] = self.to_ptes();
+        let mut allowed: u64 = WRITABLE | USER;
+        let mut denied: u64 = 0;
+        for level in [4, 3, 2, 1]{
+            let pte: PTE = table[vaddr.index(level)];
+            if !pte.is_present(){
+                return None;
+            }
+            allowed &= pte.flags().as_u64();
+            denied |= pte.flags().as_u64() & NO_EXECUTE;
+            if level == 1 || pte.is_contain(HUGE_PAGE){
+                let flags: u64 = pte.flags().as_u64() & !(HUGE_PAGE | WRITABLE | USER | NO_EXECUTE);
+                return Some(PTEFlags::new(flags | allowed | denied));
+            }
+            table = self.next_mut_table_as_array(pte);
+        }
+        None
+    }
 
-        let l2_table = self.next_mut_table_as_array(*l3_pte);
-        let l2_index: usize = vaddr.l2_index();
-        let l2_pte: &mut PTE = &mut l2_table[l2_index];
-        self.setup_next_table(l2_pte, l2_index);
+    /// Unmap the page mapping vaddr, whatever its size. Return its frame and size.
+    pub fn unmap_page(&mut self, vaddr: VirtAddr) -> Option<(PhysAddr, PageSize)>{
+        let (paddr, page_size) = self.clear_page(vaddr)?;
+        let start: VirtAddr = VirtAddr::from(vaddr.to_usize() & !(page_size.size() - 1));
+        self.flush_page(start, page_size);
+        Some((paddr, page_size))
+    }
 
-        let l1_table = self.next_mut_table_as_array(*l2_pte);
-        let l1_index: usize = vaddr.l1_index();
-        l1_table[l1_index] = PTE::new_page_entry(paddr, flags);
+    /// Clear the entry of a page, without TLB invalidation.
+    fn clear_page(&mut self, vaddr: VirtAddr) -> Option<(PhysAddr, PageSize)>{
+        let (pte, page_size) = self.get_pte(vaddr)?;
+        if !pte.is_present(){
+            return None;
+        }
+        let paddr: PhysAddr = PhysAddr::from(pte.phys_addr().to_usize() & !(page_size.size() - 1));
+        pte.set_unused();
+        Some((paddr, page_size))
     }
 
-    /// Unmap page.
-    pub fn unmap(&mut self, vaddr: VirtAddr){
-        let l1_pte = self.get_level1_pte(vaddr);
-        l1_pte.set_unused();
+    /// Unmap page. Return the frame it was mapped to.
+    pub fn unmap(&mut self, vaddr: VirtAddr) -> Option<PhysAddr>{
+        self.unmap_page(vaddr).map(|(paddr, _)| paddr)
     }
 
-    /// Map a physical region [phys_start, phys_start + size) to [virt_start, virt_start + size).
-    /// TODO: align addresses.
-    pub fn map_region(&mut self, virt_start: VirtAddr, phys_start: PhysAddr, 
-                      size: usize, flags: PTEFlags){
+    /// Map a physical region [phys_start, phys_start + size) to [virt_start, virt_start + size),
+    /// with the largest pages that fit. On failure, pages mapped so far are unmapped again.
+    pub fn map_region(&mut self, virt_start: VirtAddr, phys_start: PhysAddr,
+                      size: usize, flags: PTEFlags) -> Result<(), MapError>{
+        if size & (PAGE_SIZE - 1) != 0{
+            return Err(MapError::Misaligned);
+        }
         let mut step: usize = 0;
         while step < size{
             let virt_step: VirtAddr = virt_start + step;
             let phys_step: PhysAddr = phys_start + step;
-            self.map(virt_step, phys_step, flags);
-            step += PAGE_SIZE;
+            match self.set_largest(virt_step, phys_step, size - step, flags){
+                Ok(page_size) => {
+                    step += page_size.size();
+                }
+                Err(err) => {
+                    let _ = self.unmap_region(virt_start, step);
+                    return Err(err);
+                }
+            }
         }
+        self.flush_range(virt_start, virt_start + size);
+        Ok(())
     }
 
-    /// Unmap a virtual region [virt_start, virt_start + size).
-    pub fn unmap_region(&mut self, virt_start: VirtAddr, size: usize){
-        let mut step: usize = 0;
-        while step < size{
-            let virt_step: VirtAddr = virt_start + step;
-            self.unmap(virt_step);
-            step += PAGE_SIZE;
+    /// Unmap a virtual region [virt_start, virt_start + size). Huge pages
+    /// only partly in the region are split first. The TLB is flushed once.
+    pub fn unmap_region(&mut self, virt_start: VirtAddr, size: usize) -> Result<(), MapError>{
+        let end: usize = Self::region_end(virt_start, size)?;
+        let result = self.clear_region(virt_start, size);
+        let start: usize = virt_start.to_usize();
+        let mut freed: LinkedList = LinkedList::new();
+        self.reclaim_tables(self.to_mut_ptes(), 4, start, end, &mut freed);
+        // Paging structure caches may still point to unlinked tables.
+        self.flush_range(virt_start, VirtAddr::from(end));
+        while let Some(table) = freed.pop(){
+            page_table_free(virt_to_phys(VirtAddr::from(table as usize)));
+        }
+        result
+    }
+
+    /// End of a page aligned region that does not wrap around.
+    fn region_end(virt_start: VirtAddr, size: usize) -> Result<usize, MapError>{
+        if !virt_start.is_page_aligned() || size & (PAGE_SIZE - 1) != 0{
+            return Err(MapError::Misaligned);
+        }
+        virt_start.to_usize().checked_add(size).ok_or(MapError::NonCanonical)
+    }
+
+    /// Clear entries of a region, without TLB invalidation.
+    fn clear_region(&mut self, virt_start: VirtAddr, size: usize) -> Result<(), MapError>{
+        let end: usize = Self::region_end(virt_start, size)?;
+        let mut vaddr: usize = virt_start.to_usize();
+        while vaddr < end{
+            match self.page_size(VirtAddr::from(vaddr)){
+                Some(page_size) => {
+                    let mask: usize = page_size.size() - 1;
+                    if vaddr & mask != 0 || end - vaddr < page_size.size(){
+                        self.split_entry(VirtAddr::from(vaddr))?;
+                        continue;
+                    }
+                    self.clear_page(VirtAddr::from(vaddr));
+                    vaddr += page_size.size();
+                }
+                _ => {
+                    // Skip all that the missing entry would map.
+                    let span: usize = self.unmapped_span(VirtAddr::from(vaddr));
+                    vaddr = match (vaddr & !(span - 1)).checked_add(span){
+                        Some(next) => next,
+                        _ => { break; }
+                    };
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Size mapped by the highest entry missing on the walk to vaddr.
+    fn unmapped_span(&self, vaddr: VirtAddr) -> usize{
+        let l4_table: &[PTE] = self.to_ptes();
+        let l3_table = match self.next_table(l4_table[vaddr.l4_index()]){
+            Some(table) => table,
+            _ => { return HUGE_PAGE_SIZE_1G << 9; }
+        };
+        let l2_table = match self.next_table(l3_table[vaddr.l3_index()]){
+            Some(table) => table,
+            _ => { return HUGE_PAGE_SIZE_1G; }
+        };
+        match self.next_table(l2_table[vaddr.l2_index()]){
+            Some(_) => PAGE_SIZE,
+            _ => HUGE_PAGE_SIZE_2M,
         }
     }
 
@@ -2,6 +2,7 @@
 #![allow(unused_variables)]
 
 
+use core::fmt;
 use core::ops::{Add, AddAssign, Sub, SubAssign, BitAnd, BitOr, BitAndAssign, BitOrAssign};
 
 /// Physical address
@@ -54,6 +55,12 @@ impl PhysAddr{
     pub fn to_u64(&self) -> u64{
         self.phys_addr
     }
+
+    /// Check whether the address is at the start of a page.
+    #[inline]
+    pub const fn is_page_aligned(&self) -> bool{
+        self.phys_addr & 0xfff == 0
+    }
 }
 
 impl VirtAddr{
@@ -117,6 +124,19 @@ impl VirtAddr{
         (self.virt_addr as usize) & 0xfff
     }
 
+    /// Check whether the address is at the start of a page.
+    #[inline]
+    pub const fn is_page_aligned(&self) -> bool{
+        self.virt_addr & 0xfff == 0
+    }
+
+    /// Check whether bits 48-63 are copies of bit 47.
+    #[inline]
+    pub const fn is_canonical(&self) -> bool{
+        let high: i64 = (self.virt_addr as i64) >> 47;
+        high == 0 || high == -1
+    }
+
 }
 
 /// Override 'from' trait for physical address
@@ -283,6 +303,27 @@ impl PTEFlags{
     }
 }
 
+/// Decode flags as access rights, owner, global and caching, like "rw- K G WB".
+impl fmt::Display for PTEFlags{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        let read: char = if self.is_contain(PRESENT) { 'r' } else { '-' };
+        let write: char = if self.is_contain(WRITABLE) { 'w' } else { '-' };
+        let exec: char = if self.is_contain(NO_EXECUTE) { '-' } else { 'x' };
+        let owner: char = if self.is_contain(USER) { 'U' } else { 'K' };
+        let global: char = if self.is_contain(GLOBAL) { 'G' } else { '-' };
+        let cache: &str = if self.is_contain(NO_CACHE){
+            "UC"
+        }
+        else if self.is_contain(WRITE_THROUGH){
+            "WT"
+        }
+        else{
+            "WB"
+        };
+        write!(f, "{}{}{} {} {} {}", read, write, exec, owner, global, cache)
+    }
+}
+
 impl From<usize> for PTEFlags{
     #[inline]
     fn from(bits: usize) -> Self{
@@ -370,6 +411,10 @@ pub const GLOBAL: u64 =        1 << 8;
 /// NXE bit in the EFER register must be set.
 pub const NO_EXECUTE: u64 =    1 << 63;
 
+/// Flags a protection change may set: access rights, owner, caching and
+/// global. The page always stays present.
+pub const PROTECT_FLAGS: u64 = WRITABLE | USER | WRITE_THROUGH | NO_CACHE | GLOBAL | NO_EXECUTE;
+
 /// 64bits page table entry.
 #[derive(Clone, Copy)]
 #[repr(transparent)]
@@ -406,12 +451,24 @@ impl PTE {
         self.entry = (self.entry & !PHYS_ADDR_MASK) | (paddr.phys_addr & PHYS_ADDR_MASK);
     }
 
+    /// Get flags.
+    #[inline]
+    pub const fn flags(&self) -> PTEFlags{
+        PTEFlags{ flags: self.entry & !PHYS_ADDR_MASK }
+    }
+
     /// Set flags.
     #[inline]
     pub fn set_flags(&mut self, flags: PTEFlags){
         self.entry = (self.entry & PHYS_ADDR_MASK) | flags;
     }
 
+    /// Replace the flags selected by mask, keep the others.
+    #[inline]
+    pub fn update_flags(&mut self, mask: u64, flags: PTEFlags){
+        self.entry = (self.entry & !mask) | (flags.as_u64() & mask);
+    }
+
     /// Create a page table entry for a new page.
     #[inline]
     pub fn new_page_entry(paddr: PhysAddr, flags: PTEFlags) -> Self{
@@ -447,7 +504,7 @@ impl PTE {
     /// Determine if the pte is used.
     #[inline]
     pub fn is_unused(&self) -> bool{
-        return self.entry & 0 == 0;
+        return self.entry == 0;
     }
 
 }
\ No newline at end of file
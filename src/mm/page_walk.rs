@@ -0,0 +1,178 @@
+#![allow(dead_code)]
+
+use crate::println;
+
+use super::page_table::{PageSize, PageTable};
+use super::page_table_entry::{PhysAddr, VirtAddr, PTE, PTEFlags, ACCESSED, DIRTY, HUGE_PAGE};
+
+/// A run of pages mapped to contiguous physical memory with the same flags.
+#[derive(Clone, Copy)]
+pub struct Mapping{
+    pub virt_start: VirtAddr,
+    pub phys_start: PhysAddr,
+    // Size in bytes.
+    pub size: usize,
+    pub page_size: PageSize,
+    // Flags of every page, without the huge page, accessed and dirty bits.
+    pub flags: PTEFlags,
+}
+
+impl Mapping{
+    /// Check whether next continues this run.
+    fn is_followed_by(&self, next: &Mapping) -> bool{
+        self.virt_start.to_usize() + self.size == next.virt_start.to_usize()
+            && self.phys_start.to_usize() + self.size == next.phys_start.to_usize()
+            && self.page_size == next.page_size
+            && self.flags.as_u64() == next.flags.as_u64()
+    }
+
+    /// Print this run.
+    pub fn print(&self){
+        let virt_last: usize = self.virt_start.to_usize() + (self.size - 1);
+        let phys_last: usize = self.phys_start.to_usize() + (self.size - 1);
+        println!("[+] {:016x}-{:016x} -> {:012x}-{:012x} {:>9} KB {:?} {}", self.virt_start.to_usize(),
+            virt_last, self.phys_start.to_usize(), phys_last, self.size / 1024, self.page_size, self.flags);
+    }
+}
+
+/// Sign-extend bit 47 of an address, for level 4 entries of the kernel half.
+fn canonical(addr: usize) -> usize{
+    if addr & (1 << 47) != 0{
+        addr | 0xffff_0000_0000_0000
+    }
+    else{
+        addr
+    }
+}
+
+/// Size of a page mapped at a level.
+fn level_page_size(level: usize) -> PageSize{
+    match level{
+        3 => PageSize::Size1G,
+        2 => PageSize::Size2M,
+        _ => PageSize::Size4K,
+    }
+}
+
+/// Walk state: the walked table, the current run, and the visitor it is
+/// handed to. With hidden, 4K entries that are not present but keep a frame
+/// are visited too.
+struct Walker<'a>{
+    page_table: &'a PageTable,
+    hidden: bool,
+    run: Option<Mapping>,
+    visitor: &'a mut dyn FnMut(&Mapping),
+}
+
+impl<'a> Walker<'a>{
+    /// Add a page, finishing the current run if it does not continue it.
+    fn push(&mut self, next: Mapping){
+        if let Some(run) = self.run.as_mut(){
+            if run.is_followed_by(&next){
+                run.size += next.size;
+                return ;
+            }
+            (self.visitor)(run);
+        }
+        self.run = Some(next);
+    }
+
+    /// Hand over the last run.
+    fn finish(&mut self){
+        if let Some(run) = self.run.take(){
+            (self.visitor)(&run);
+        }
+    }
+
+    /// Visit entries of a table covering [first, last], both inclusive. base
+    /// is the address mapped by the first entry.
+    fn walk_table(&mut self, table: &[PTE], level: usize, base: usize, first: usize, last: usize){
+        let shift: usize = 12 + 9 * (level - 1);
+        for (index, pte) in table.iter().enumerate(){
+            let mut entry_first: usize = base + (index << shift);
+            if level == 4{
+                entry_first = canonical(entry_first);
+            }
+            let entry_last: usize = entry_first + ((1 << shift) - 1);
+            if entry_last < first || entry_first > last{
+                continue;
+            }
+            if !pte.is_present() && !(self.hidden && level == 1 && !pte.is_unused()){
+                continue;
+            }
+
+            if level > 1{
+                if let Some(next_table) = self.page_table.next_table(*pte){
+                    self.walk_table(next_table, level - 1, entry_first, first, last);
+                    continue;
+                }
+            }
+
+            // Leaf, clipped to the walked range.
+            let page_size: PageSize = level_page_size(level);
+            let paddr: usize = pte.phys_addr().to_usize() & !(page_size.size() - 1);
+            let virt_first: usize = entry_first.max(first);
+            let virt_last: usize = entry_last.min(last);
+            self.push(Mapping{
+                virt_start: VirtAddr::from(virt_first),
+                phys_start: PhysAddr::from(paddr + (virt_first - entry_first)),
+                size: virt_last - virt_first + 1,
+                page_size: page_size,
+                flags: pte.flags() & !(HUGE_PAGE | ACCESSED | DIRTY),
+            });
+        }
+    }
+}
+
+impl PageTable{
+    /// Visit every mapping in [start, end), contiguous runs merged.
+    pub fn walk_range(&self, start: VirtAddr, end: VirtAddr, visitor: &mut dyn FnMut(&Mapping)){
+        if start >= end{
+            return ;
+        }
+        self.walk_inclusive(start.to_usize(), end.to_usize() - 1, false, visitor);
+    }
+
+    /// Visit every mapping in [start, end), and 4K pages made not present
+    /// that keep their frame. Runs of those have no PRESENT flag.
+    pub fn walk_range_hidden(&self, start: VirtAddr, end: VirtAddr, visitor: &mut dyn FnMut(&Mapping)){
+        if start >= end{
+            return ;
+        }
+        self.walk_inclusive(start.to_usize(), end.to_usize() - 1, true, visitor);
+    }
+
+    /// Visit every mapping of the address space, contiguous runs merged.
+    pub fn walk(&self, visitor: &mut dyn FnMut(&Mapping)){
+        self.walk_inclusive(0, usize::MAX, false, visitor);
+    }
+
+    /// Visit mappings in [first, last].
+    fn walk_inclusive(&self, first: usize, last: usize, hidden: bool, visitor: &mut dyn FnMut(&Mapping)){
+        let mut walker: Walker = Walker{ page_table: self, hidden: hidden, run: None, visitor: visitor };
+        walker.walk_table(self.to_ptes(), 4, 0, first, last);
+        walker.finish();
+    }
+
+    /// Print mappings in [start, end).
+    pub fn dump_range(&self, start: VirtAddr, end: VirtAddr){
+        println!("[+] Mappings 0x{:x} - 0x{:x}:", start.to_usize(), end.to_usize());
+        let mut count: usize = 0;
+        self.walk_range(start, end, &mut |mapping: &Mapping| {
+            mapping.print();
+            count += 1;
+        });
+        println!("[+] {} mappings.", count);
+    }
+
+    /// Print the whole address space.
+    pub fn dump(&self){
+        println!("[+] Mappings:");
+        let mut count: usize = 0;
+        self.walk(&mut |mapping: &Mapping| {
+            mapping.print();
+            count += 1;
+        });
+        println!("[+] {} mappings.", count);
+    }
+}
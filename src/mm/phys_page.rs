@@ -4,39 +4,230 @@ use core::slice::from_raw_parts_mut;
 use spin::Mutex;
 
 use super::page_table_entry::{PhysAddr, VirtAddr};
-use crate::utils::linked_list::LinkedList;
+use super::buddy::BuddyAllocator;
+use super::frame::{frame_table_init, page_of, set_frames_type, PageType, NO_ORDER};
 
 /// Set normal page size as 4k.
 pub const PAGE_SIZE: usize = 4096;
-pub const PHYS_TO_VIRT_BASE: usize = 0;
+/// All physical memory is mapped at this offset.
+pub const PHYS_TO_VIRT_BASE: usize = 0xffff800000000000;
 
-lazy_static!{
-    // Start of kernel free memory.
-    pub static ref FREE_MEM_BASE: Mutex<PhysAddr> = Mutex::new(PhysAddr::from(0usize));
-    // Top of kernel heap.
-    pub static ref KERNEL_HEAP_TOP: Mutex<usize> = Mutex::new(0usize);
-    // Free memory list.
-    pub static ref FREE_MEM_LIST: Mutex<LinkedList> = Mutex::new(LinkedList::new());
-}
+/// Maximum number of usable physical memory areas.
+pub const MAX_PHYS_AREAS: usize = 32;
+/// Memory below 1MB belongs to BIOS, VGA and the boot code.
+pub const LOW_MEM_LIMIT: usize = 0x100000;
+/// Only the first 4GB are in the direct map built at boot. Frames above it
+/// are allocated once the kernel page table is enabled.
+pub const BOOT_MAPPED_LIMIT: usize = 0x100000000;
 
-/// Initialize kernel heap.
+/// Align address down to page boundary.
 #[inline]
-pub fn kernel_heap_init(free_mem_base: &PhysAddr){
-    FREE_MEM_BASE.lock().clone_from(free_mem_base);
-    *KERNEL_HEAP_TOP.lock() = FREE_MEM_BASE.lock().to_usize();
+pub const fn page_align_down(addr: usize) -> usize{
+    addr & !(PAGE_SIZE - 1)
 }
 
-/// Allocate next physical page direcly from kernel heap.
+/// Align address up to page boundary.
 #[inline]
-pub fn alloc_next_frame() -> PhysAddr{
-    *KERNEL_HEAP_TOP.lock() += PAGE_SIZE;
-    PhysAddr::from(*KERNEL_HEAP_TOP.lock())
+pub const fn page_align_up(addr: usize) -> usize{
+    (addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+/// A physical memory area [start, end).
+#[derive(Clone, Copy)]
+pub struct PhysArea{
+    pub start: usize,
+    pub end: usize,
+}
+
+impl PhysArea{
+    /// Create an empty area.
+    pub const fn empty() -> Self{
+        Self{ start: 0, end: 0 }
+    }
+
+    /// Size of this area in bytes.
+    #[inline]
+    pub fn size(&self) -> usize{
+        self.end - self.start
+    }
+}
+
+/// Usable physical memory, sorted by start address.
+pub struct PhysAreas{
+    areas: [PhysArea; MAX_PHYS_AREAS],
+    num_areas: usize,
+}
+
+impl PhysAreas{
+    /// Create an empty area set.
+    pub const fn new() -> Self{
+        Self{ areas: [PhysArea::empty(); MAX_PHYS_AREAS], num_areas: 0 }
+    }
+
+    /// Number of areas.
+    pub fn len(&self) -> usize{
+        self.num_areas
+    }
+
+    /// Get area by index.
+    pub fn get(&self, index: usize) -> Option<PhysArea>{
+        if index < self.num_areas{
+            Some(self.areas[index])
+        }
+        else{
+            None
+        }
+    }
+
+    /// Insert an area at index, keeping the array sorted.
+    fn insert(&mut self, index: usize, area: PhysArea) -> bool{
+        if self.num_areas == MAX_PHYS_AREAS{
+            return false;
+        }
+        let mut i: usize = self.num_areas;
+        while i > index{
+            self.areas[i] = self.areas[i - 1];
+            i -= 1;
+        }
+        self.areas[index] = area;
+        self.num_areas += 1;
+        true
+    }
+
+    /// Remove the area at index.
+    fn remove(&mut self, index: usize){
+        for i in index..self.num_areas - 1{
+            self.areas[i] = self.areas[i + 1];
+        }
+        self.num_areas -= 1;
+    }
+
+    /// Add usable memory [start, end). Partial pages are dropped.
+    pub fn add(&mut self, start: usize, end: usize) -> bool{
+        let start: usize = page_align_up(start);
+        let end: usize = page_align_down(end);
+        if start >= end{
+            return true;
+        }
+
+        let mut index: usize = 0;
+        while index < self.num_areas && self.areas[index].start < start{
+            index += 1;
+        }
+        self.insert(index, PhysArea{ start: start, end: end })
+    }
+
+    /// Remove [start, end) from usable memory. Partial pages are reserved as a whole.
+    pub fn reserve(&mut self, start: usize, end: usize){
+        let start: usize = page_align_down(start);
+        let end: usize = page_align_up(end);
+        if start >= end{
+            return ;
+        }
+
+        let mut index: usize = 0;
+        while index < self.num_areas{
+            let area: PhysArea = self.areas[index];
+            if area.end <= start || area.start >= end{
+                index += 1;
+                continue;
+            }
+
+            if area.start >= start && area.end <= end{
+                // Fully covered.
+                self.remove(index);
+                continue;
+            }
+
+            if area.start < start && area.end > end{
+                // Split into two areas.
+                self.areas[index].end = start;
+                // If there is no room left, the upper part is simply lost.
+                self.insert(index + 1, PhysArea{ start: end, end: area.end });
+                index += 2;
+                continue;
+            }
+
+            if area.start < start{
+                self.areas[index].end = start;
+            }
+            else{
+                self.areas[index].start = end;
+            }
+            index += 1;
+        }
+    }
+
+    /// Total usable bytes.
+    pub fn total_size(&self) -> usize{
+        let mut total: usize = 0;
+        for i in 0..self.num_areas{
+            total += self.areas[i].size();
+        }
+        total
+    }
+}
+
+lazy_static!{
+    // Usable physical memory areas.
+    pub static ref PHYS_AREAS: Mutex<PhysAreas> = Mutex::new(PhysAreas::new());
+    // Buddy allocator of free physical pages.
+    pub static ref FRAME_ALLOCATOR: Mutex<BuddyAllocator> = Mutex::new(BuddyAllocator::new());
+}
+
+/// Register usable physical memory [start, end).
+pub fn phys_area_add(start: usize, end: usize){
+    let start: usize = if start < LOW_MEM_LIMIT { LOW_MEM_LIMIT } else { start };
+    if start >= end{
+        return ;
+    }
+    if !PHYS_AREAS.lock().add(start, end){
+        crate::println!("[Err] Too many memory areas, drop 0x{:x}-0x{:x}.", start, end);
+    }
+}
+
+/// Exclude physical memory [start, end) from allocation.
+pub fn phys_area_reserve(start: usize, end: usize){
+    PHYS_AREAS.lock().reserve(start, end);
+}
+
+/// Initialize physical memory allocation. Must be called after all areas
+/// have been added and reserved. Only frames below BOOT_MAPPED_LIMIT are
+/// handed to the allocator, see phys_mem_init_high.
+pub fn phys_mem_init(){
+    let mut areas = PHYS_AREAS.lock();
+    if !frame_table_init(&mut areas){
+        crate::println!("[Err] No room for the frame table.");
+    }
+
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    for i in 0..areas.len(){
+        let area: PhysArea = areas.get(i).unwrap();
+        if area.start < BOOT_MAPPED_LIMIT{
+            let end: usize = if area.end > BOOT_MAPPED_LIMIT { BOOT_MAPPED_LIMIT } else { area.end };
+            allocator.add_area(area.start, end);
+        }
+    }
+}
+
+/// Hand frames above BOOT_MAPPED_LIMIT to the allocator. Must be called
+/// once the direct map covers all memory.
+pub fn phys_mem_init_high(){
+    let areas = PHYS_AREAS.lock();
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    for i in 0..areas.len(){
+        let area: PhysArea = areas.get(i).unwrap();
+        if area.end > BOOT_MAPPED_LIMIT{
+            let start: usize = if area.start < BOOT_MAPPED_LIMIT { BOOT_MAPPED_LIMIT } else { area.start };
+            allocator.add_area(start, area.end);
+        }
+    }
 }
 
 /// Set the whole physical page to a value.
 #[inline]
 pub fn set_frame(frame: PhysAddr, val: u8){
-    let mut frame_content: &mut [u8] = unsafe{from_raw_parts_mut(frame.to_mut_ptr(), PAGE_SIZE)};
+    let frame_content: &mut [u8] = unsafe{from_raw_parts_mut(phys_to_virt(frame).to_mut_ptr() as *mut u8, PAGE_SIZE)};
     for i in 0..PAGE_SIZE{
         frame_content[i] = val;
     }
@@ -47,36 +238,87 @@ pub fn simple_phys_to_virt(paddr: PhysAddr) -> VirtAddr{
     VirtAddr::from(paddr.to_usize() + PHYS_TO_VIRT_BASE)
 }
 
-/// TODO: Delicated physical to virtual translation.
+/// Physical to virtual translation through the direct map.
 pub fn phys_to_virt(paddr: PhysAddr) -> VirtAddr{
     simple_phys_to_virt(paddr)
 }
 
-/// Simple allocator: use a linked list as free memory pool.
-pub fn phys_page_alloc() -> Option<PhysAddr>{
-    if !FREE_MEM_LIST.lock().is_empty(){
-        let result: Option<*mut usize> = FREE_MEM_LIST.lock().pop();
-        match result{
-            Some(free_page) => {
-                Some(PhysAddr::from(free_page))
-            }
-            _ =>{
-                None
-            }
-        }
+/// Simple virtual to physical translation by direct-mapping.
+pub fn simple_virt_to_phys(vaddr: VirtAddr) -> PhysAddr{
+    PhysAddr::from(vaddr.to_usize() - PHYS_TO_VIRT_BASE)
+}
+
+/// Translate an address returned by phys_to_virt back to physical.
+pub fn virt_to_phys(vaddr: VirtAddr) -> PhysAddr{
+    simple_virt_to_phys(vaddr)
+}
+
+/// Allocate 2^order physically contiguous pages for a given use, aligned
+/// to their size. The content is not cleared. Return None if no such block is left.
+pub fn phys_pages_alloc_typed(order: usize, page_type: PageType) -> Option<PhysAddr>{
+    let paddr: PhysAddr = FRAME_ALLOCATOR.lock().alloc(order)?;
+    set_frames_type(paddr, order, page_type);
+    if let Some(page) = page_of(paddr){
+        page.set_order(order);
+        page.set_refcount(1);
     }
-    else{
-        let frame: PhysAddr = {
-            let next_frame: PhysAddr = alloc_next_frame();
-            set_frame(next_frame, 0);
-            next_frame
-        };
-        Some(frame)
+    Some(paddr)
+}
+
+/// Allocate 2^order physically contiguous kernel pages.
+pub fn phys_pages_alloc(order: usize) -> Option<PhysAddr>{
+    phys_pages_alloc_typed(order, PageType::Kernel)
+}
+
+/// Free 2^order contiguous pages allocated by phys_pages_alloc.
+pub fn phys_pages_free(paddr: PhysAddr, order: usize){
+    if let Some(page) = page_of(paddr){
+        page.set_refcount(0);
+        page.set_order(NO_ORDER);
     }
+    set_frames_type(paddr, order, PageType::Free);
+    FRAME_ALLOCATOR.lock().free(paddr, order);
+}
+
+/// Allocate a zeroed physical page. Return None if memory is exhausted.
+pub fn phys_page_alloc() -> Option<PhysAddr>{
+    let frame: PhysAddr = phys_pages_alloc(0)?;
+    set_frame(frame, 0);
+    Some(frame)
 }
 
 /// Free a physical page.
 pub fn phys_page_free(paddr: PhysAddr){
-    let free_node: *mut usize = paddr.to_mut_ptr() as *mut usize;
-    FREE_MEM_LIST.lock().push(free_node);
-}
\ No newline at end of file
+    phys_pages_free(paddr, 0);
+}
+
+/// Take another reference to a block from phys_pages_alloc. Frames not
+/// allocated as a block are left alone.
+pub fn phys_page_get(paddr: PhysAddr){
+    if let Some(page) = page_of(paddr){
+        if page.page_type() != PageType::Free && page.page_type() != PageType::Reserved && page.refcount() != 0{
+            page.get();
+        }
+    }
+}
+
+/// Number of references to a block from phys_pages_alloc.
+pub fn phys_page_refcount(paddr: PhysAddr) -> u32{
+    page_of(paddr).map(|page| page.refcount()).unwrap_or(0)
+}
+
+/// Drop a reference to a block from phys_pages_alloc, and free it with the
+/// last one. Frames not allocated as a block, like MMIO, are left alone.
+pub fn phys_page_put(paddr: PhysAddr){
+    let page = match page_of(paddr){
+        Some(page) => page,
+        _ => { return ; }
+    };
+    if page.page_type() == PageType::Free || page.page_type() == PageType::Reserved || page.refcount() == 0{
+        return ;
+    }
+    if page.put() == 0{
+        let order: usize = page.order();
+        phys_pages_free(paddr, order);
+    }
+}
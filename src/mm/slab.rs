@@ -0,0 +1,343 @@
+#![allow(dead_code)]
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use spin::Mutex;
+
+use crate::utils::linked_list::LinkedList;
+use crate::println;
+
+use super::buddy::order_size;
+use super::page_table_entry::{PhysAddr, VirtAddr};
+use super::frame::PageType;
+use super::phys_page::{phys_pages_alloc_typed, phys_pages_free, phys_to_virt, virt_to_phys};
+
+/// Maximum number of CPUs with their own magazines.
+pub const MAX_CPUS: usize = 8;
+/// Objects cached by one magazine.
+pub const MAGAZINE_SIZE: usize = 16;
+/// Largest slab, 2^5 pages (128KB).
+pub const SLAB_MAX_ORDER: usize = 5;
+
+/// Current CPU index.
+/// TODO: read from per-CPU data once other CPUs are brought up.
+#[inline]
+pub fn curr_cpu() -> usize{
+    0
+}
+
+/// Object constructor, run once when a slab is populated.
+pub type SlabCtor = fn(*mut u8);
+
+/// Slab header, at the start of every slab.
+#[repr(C)]
+struct Slab{
+    // Link in the cache slab lists. Must be the first word.
+    next: usize,
+    // Free objects of this slab.
+    free: LinkedList,
+    // Objects taken out of this slab.
+    in_use: usize,
+}
+
+/// Per-CPU stack of free objects, refilled and flushed in batches.
+struct Magazine{
+    objs: [usize; MAGAZINE_SIZE],
+    count: usize,
+}
+
+impl Magazine{
+    const fn new() -> Self{
+        Self{ objs: [0; MAGAZINE_SIZE], count: 0 }
+    }
+}
+
+/// Slab lists of a cache.
+struct SlabLists{
+    // Slabs with both used and free objects.
+    partial: LinkedList,
+    // Slabs without used objects. At most one is kept.
+    empty: LinkedList,
+    num_slabs: usize,
+    in_use: usize,
+}
+
+/// Statistics of a slab cache.
+#[derive(Clone, Copy)]
+pub struct SlabStats{
+    pub name: &'static str,
+    pub obj_size: usize,
+    // Objects handed out to callers.
+    pub in_use: usize,
+    // Free objects in per-CPU magazines.
+    pub cached: usize,
+    pub slabs: usize,
+    // Slab bytes that can never hold objects, plus object padding.
+    pub wasted: usize,
+}
+
+/// Cache of fixed-size objects.
+pub struct SlabCache{
+    name: &'static str,
+    // Size asked by the creator.
+    size: usize,
+    // Distance between two objects.
+    stride: usize,
+    // Offset of the free link inside an object.
+    link_offset: usize,
+    // Offset of the first object in a slab.
+    first_offset: usize,
+    objs_per_slab: usize,
+    slab_order: usize,
+    ctor: Option<SlabCtor>,
+    lists: Mutex<SlabLists>,
+    magazines: [Mutex<Magazine>; MAX_CPUS],
+}
+
+impl SlabCache{
+    /// Create a cache of objects with given size and alignment.
+    /// Return None if one object doesn't fit in the largest slab.
+    pub fn new(name: &'static str, size: usize, align: usize, ctor: Option<SlabCtor>) -> Option<Self>{
+        let word: usize = size_of::<usize>();
+        let align: usize = if align < word { word } else { align };
+        let size_words: usize = (size + word - 1) & !(word - 1);
+
+        // Objects with a constructor keep their content while free, so the
+        // free link goes after the object instead of over it.
+        let link_offset: usize = if ctor.is_some() { size_words } else { 0 };
+        let obj_bytes: usize = if ctor.is_some() { size_words + word } else { size_words };
+        let stride: usize = (obj_bytes + align - 1) & !(align - 1);
+        let first_offset: usize = (size_of::<Slab>() + align - 1) & !(align - 1);
+
+        // Smallest slab wasting no more than 1/8 of its space.
+        let mut slab_order: usize = 0;
+        loop{
+            let slab_bytes: usize = order_size(slab_order);
+            if slab_bytes >= first_offset + stride{
+                // Space left after the objects, and padding of each object,
+                // as counted by stats().
+                let objs: usize = (slab_bytes - first_offset) / stride;
+                let wasted: usize = (slab_bytes - objs * stride) + objs * (stride - size);
+                if wasted * 8 <= slab_bytes || slab_order == SLAB_MAX_ORDER{
+                    break;
+                }
+            }
+            else if slab_order == SLAB_MAX_ORDER{
+                return None;
+            }
+            slab_order += 1;
+        }
+
+        Some(Self{
+            name: name,
+            size: size,
+            stride: stride,
+            link_offset: link_offset,
+            first_offset: first_offset,
+            objs_per_slab: (order_size(slab_order) - first_offset) / stride,
+            slab_order: slab_order,
+            ctor: ctor,
+            lists: Mutex::new(SlabLists{
+                partial: LinkedList::new(),
+                empty: LinkedList::new(),
+                num_slabs: 0,
+                in_use: 0,
+            }),
+            magazines: core::array::from_fn(|_| Mutex::new(Magazine::new())),
+        })
+    }
+
+    /// Name of this cache.
+    pub fn name(&self) -> &'static str{
+        self.name
+    }
+
+    /// Object size asked by the creator.
+    pub fn obj_size(&self) -> usize{
+        self.size
+    }
+
+    /// Slab owning an object.
+    #[inline]
+    fn slab_of(&self, obj: usize) -> *mut Slab{
+        (obj & !(order_size(self.slab_order) - 1)) as *mut Slab
+    }
+
+    /// Allocate and populate a new slab.
+    fn new_slab(&self) -> Option<*mut Slab>{
+        let paddr: PhysAddr = phys_pages_alloc_typed(self.slab_order, PageType::Slab)?;
+        let base: usize = phys_to_virt(paddr).to_usize();
+        let slab: *mut Slab = base as *mut Slab;
+        unsafe{
+            (*slab).next = 0;
+            (*slab).free = LinkedList::new();
+            (*slab).in_use = 0;
+            // Push in reverse so objects are handed out in address order.
+            let mut i: usize = self.objs_per_slab;
+            while i > 0{
+                i -= 1;
+                let obj: usize = base + self.first_offset + i * self.stride;
+                if let Some(ctor) = self.ctor{
+                    ctor(obj as *mut u8);
+                }
+                (*slab).free.push((obj + self.link_offset) as *mut usize);
+            }
+        }
+        Some(slab)
+    }
+
+    /// Take one object from the slab lists.
+    fn alloc_from_slabs(&self, lists: &mut SlabLists) -> Option<usize>{
+        if lists.partial.is_empty(){
+            let slab: *mut usize = match lists.empty.pop(){
+                Some(slab) => slab,
+                _ => {
+                    let slab: *mut Slab = self.new_slab()?;
+                    lists.num_slabs += 1;
+                    slab as *mut usize
+                }
+            };
+            lists.partial.push(slab);
+        }
+
+        // The object always comes from the head of the partial list.
+        let slab: *mut Slab = lists.partial.peek()? as *mut Slab;
+        let obj: usize = unsafe{
+            let link: *mut usize = (*slab).free.pop()?;
+            (*slab).in_use += 1;
+            if (*slab).free.is_empty(){
+                lists.partial.pop();
+            }
+            link as usize - self.link_offset
+        };
+        lists.in_use += 1;
+        Some(obj)
+    }
+
+    /// Return one object to its slab.
+    fn free_to_slabs(&self, lists: &mut SlabLists, obj: usize){
+        let slab: *mut Slab = self.slab_of(obj);
+        lists.in_use -= 1;
+        unsafe{
+            let was_full: bool = (*slab).free.is_empty();
+            (*slab).free.push((obj + self.link_offset) as *mut usize);
+            (*slab).in_use -= 1;
+
+            if (*slab).in_use != 0{
+                if was_full{
+                    lists.partial.push(slab as *mut usize);
+                }
+                return ;
+            }
+
+            // The slab is empty now.
+            if !was_full{
+                for node in lists.partial.iter_mut(){
+                    if node.value() == slab as *mut usize{
+                        node.pop();
+                        break;
+                    }
+                }
+            }
+        }
+
+        if lists.empty.is_empty(){
+            lists.empty.push(slab as *mut usize);
+        }
+        else{
+            let paddr: PhysAddr = virt_to_phys(VirtAddr::from(slab as usize));
+            phys_pages_free(paddr, self.slab_order);
+            lists.num_slabs -= 1;
+        }
+    }
+
+    /// Allocate an object. Objects of caches with a constructor are returned
+    /// constructed, and must be freed in that state.
+    pub fn alloc(&self) -> Option<*mut u8>{
+        let mut magazine = self.magazines[curr_cpu()].lock();
+        if magazine.count == 0{
+            // Refill half a magazine at once.
+            let mut lists = self.lists.lock();
+            while magazine.count < MAGAZINE_SIZE / 2{
+                match self.alloc_from_slabs(&mut lists){
+                    Some(obj) => {
+                        let count: usize = magazine.count;
+                        magazine.objs[count] = obj;
+                        magazine.count += 1;
+                    }
+                    _ => {
+                        break;
+                    }
+                }
+            }
+            if magazine.count == 0{
+                return None;
+            }
+        }
+
+        magazine.count -= 1;
+        Some(magazine.objs[magazine.count] as *mut u8)
+    }
+
+    /// Free an object allocated from this cache.
+    pub fn free(&self, obj: *mut u8){
+        let mut magazine = self.magazines[curr_cpu()].lock();
+        if magazine.count == MAGAZINE_SIZE{
+            // Flush half a magazine at once.
+            let mut lists = self.lists.lock();
+            while magazine.count > MAGAZINE_SIZE / 2{
+                magazine.count -= 1;
+                let flushed: usize = magazine.objs[magazine.count];
+                self.free_to_slabs(&mut lists, flushed);
+            }
+        }
+
+        let count: usize = magazine.count;
+        magazine.objs[count] = obj as usize;
+        magazine.count += 1;
+    }
+
+    /// Get statistics of this cache.
+    pub fn stats(&self) -> SlabStats{
+        let mut cached: usize = 0;
+        for magazine in self.magazines.iter(){
+            cached += magazine.lock().count;
+        }
+
+        let lists = self.lists.lock();
+        let slab_bytes: usize = order_size(self.slab_order);
+        let slab_waste: usize = slab_bytes - self.objs_per_slab * self.stride;
+        let obj_waste: usize = self.stride - self.size;
+        SlabStats{
+            name: self.name,
+            obj_size: self.size,
+            in_use: lists.in_use - cached,
+            cached: cached,
+            slabs: lists.num_slabs,
+            wasted: lists.num_slabs * slab_waste + lists.in_use * obj_waste,
+        }
+    }
+}
+
+lazy_static!{
+    // All slab caches, for statistics.
+    pub static ref SLAB_CACHES: Mutex<Vec<&'static SlabCache>> = Mutex::new(Vec::new());
+}
+
+/// Create a named slab cache that lives forever.
+pub fn slab_cache_create(name: &'static str, size: usize, align: usize, ctor: Option<SlabCtor>) -> Option<&'static SlabCache>{
+    let cache: &'static SlabCache = Box::leak(Box::new(SlabCache::new(name, size, align, ctor)?));
+    SLAB_CACHES.lock().push(cache);
+    Some(cache)
+}
+
+/// Print statistics of all slab caches.
+pub fn slab_info(){
+    println!("[+] slab cache        size   in use   cached  slabs   wasted");
+    for cache in SLAB_CACHES.lock().iter(){
+        let stats: SlabStats = cache.stats();
+        println!("[+] {:<16} {:>6} {:>8} {:>8} {:>6} {:>8}", stats.name, stats.obj_size,
+            stats.in_use, stats.cached, stats.slabs, stats.wasted);
+    }
+}
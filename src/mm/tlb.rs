@@ -0,0 +1,145 @@
+#![allow(dead_code)]
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::Mutex;
+
+use crate::asms::cpuid::{cpu_has_pcid, cpu_has_pge};
+use crate::println;
+
+use super::page_table::{lcr3, lcr4, rcr3, rcr4, CR4_PCIDE, CR4_PGE};
+use super::page_table_entry::{PhysAddr, VirtAddr};
+use super::phys_page::{PAGE_SIZE, PHYS_TO_VIRT_BASE};
+
+/// Number of process context identifiers, 0 is the kernel's.
+pub const NUM_PCID: usize = 4096;
+/// Low bits of cr3 holding the PCID.
+pub const CR3_PCID_MASK: usize = 0xfff;
+/// Keep TLB entries of the new PCID on a cr3 write.
+pub const CR3_NOFLUSH: usize = 1 << 63;
+/// Ranges longer than this many pages flush the whole TLB instead.
+pub const TLB_FLUSH_MAX_PAGES: usize = 32;
+
+// Features turned on by tlb_init.
+static PGE_ENABLED: AtomicBool = AtomicBool::new(false);
+static PCID_ENABLED: AtomicBool = AtomicBool::new(false);
+
+// Next PCID handed out. PCIDs wrap around and get shared.
+static NEXT_PCID: AtomicUsize = AtomicUsize::new(1);
+
+// Page table last loaded with each PCID. Its TLB entries can be kept when
+// the same table is loaded again, unless it changed while not loaded.
+static PCID_OWNER: Mutex<[usize; NUM_PCID]> = Mutex::new([0; NUM_PCID]);
+
+/// Invalidate TLB entries of one page.
+#[cfg(target_arch = "x86_64")]
+pub fn invlpg(vaddr: VirtAddr){
+    unsafe{
+        asm!("invlpg [{}]", in(reg) vaddr.to_usize(), options(nostack));
+    }
+}
+
+/// Check whether a virtual address is in the kernel half, which every page
+/// table shares.
+#[inline]
+pub fn is_kernel_addr(vaddr: VirtAddr) -> bool{
+    vaddr.to_usize() >= PHYS_TO_VIRT_BASE
+}
+
+/// Enable global pages and PCID if the CPU has them.
+pub fn tlb_init(){
+    let mut cr4: usize = rcr4();
+    if cpu_has_pge(){
+        cr4 |= CR4_PGE;
+        PGE_ENABLED.store(true, Ordering::Release);
+    }
+    // PCIDE can only be set while the current PCID is 0.
+    if cpu_has_pcid() && rcr3() & CR3_PCID_MASK == 0{
+        cr4 |= CR4_PCIDE;
+        PCID_ENABLED.store(true, Ordering::Release);
+    }
+    lcr4(cr4);
+    println!("[+] TLB: global pages {}, PCID {}", PGE_ENABLED.load(Ordering::Acquire),
+        PCID_ENABLED.load(Ordering::Acquire));
+}
+
+/// Check whether PCIDs are in use.
+pub fn pcid_enabled() -> bool{
+    PCID_ENABLED.load(Ordering::Acquire)
+}
+
+/// Get a PCID for a new page table.
+pub fn pcid_alloc() -> usize{
+    let pcid: usize = NEXT_PCID.fetch_add(1, Ordering::Relaxed);
+    pcid % (NUM_PCID - 1) + 1
+}
+
+/// Forget TLB entries kept for a page table that is not loaded.
+pub fn pcid_invalidate(base: PhysAddr, pcid: usize){
+    let mut owners = PCID_OWNER.lock();
+    if owners[pcid & CR3_PCID_MASK] == base.to_usize(){
+        owners[pcid & CR3_PCID_MASK] = 0;
+    }
+}
+
+/// Load a page table. With PCID, entries it left in the TLB are kept if
+/// nothing changed since it was last loaded.
+pub fn load_page_table(base: PhysAddr, pcid: usize){
+    if !pcid_enabled(){
+        lcr3(base.to_usize());
+        return ;
+    }
+    let pcid: usize = pcid & CR3_PCID_MASK;
+    let mut owners = PCID_OWNER.lock();
+    if owners[pcid] == base.to_usize(){
+        lcr3(base.to_usize() | pcid | CR3_NOFLUSH);
+    }
+    else{
+        owners[pcid] = base.to_usize();
+        lcr3(base.to_usize() | pcid);
+    }
+}
+
+/// Flush all TLB entries, global ones included.
+pub fn flush_tlb_all(){
+    if PGE_ENABLED.load(Ordering::Acquire){
+        // Toggling PGE flushes everything, for every PCID.
+        let cr4: usize = rcr4();
+        lcr4(cr4 & !CR4_PGE);
+        lcr4(cr4);
+    }
+    else{
+        // Only the current PCID is flushed, so every page table must be
+        // flushed again when it is next loaded.
+        if pcid_enabled(){
+            PCID_OWNER.lock().fill(0);
+        }
+        flush_tlb_local();
+    }
+}
+
+/// Flush non-global TLB entries of the current page table.
+pub fn flush_tlb_local(){
+    // Writing cr3 without the no-flush bit drops the current PCID entries.
+    lcr3(rcr3() & !CR3_NOFLUSH);
+}
+
+/// Flush pages [start, end) of the current page table. Long ranges flush
+/// the whole TLB.
+pub fn flush_tlb_range(start: VirtAddr, end: VirtAddr){
+    let pages: usize = (end.to_usize() - start.to_usize() + PAGE_SIZE - 1) / PAGE_SIZE;
+    if pages > TLB_FLUSH_MAX_PAGES{
+        if is_kernel_addr(start){
+            flush_tlb_all();
+        }
+        else{
+            flush_tlb_local();
+        }
+        return ;
+    }
+    let mut vaddr: VirtAddr = start;
+    while vaddr < end{
+        invlpg(vaddr);
+        vaddr += PAGE_SIZE;
+    }
+}
@@ -19,6 +19,14 @@ impl LinkedList{
         self.head.is_null()
     }
 
+    /// Get front without removing it.
+    pub fn peek(&self) -> Option<*mut usize>{
+        match self.is_empty() {
+            true => None,
+            false => Some(self.head),
+        }
+    }
+
     /// Push to front.
     pub fn push(&mut self, item: *mut usize){
         unsafe{*item = self.head as usize;}